@@ -1,67 +1,115 @@
-use crate::solana_sdk::clock::UnixTimestamp;
+use crate::solana_sdk::clock::{Clock, Epoch, Slot, UnixTimestamp, MAX_PROCESSING_AGE};
 use std::{
+    borrow::Cow,
+    cell::RefCell,
     collections::{HashMap, HashSet},
-    convert::TryInto,
-    path::Path,
-    sync::{atomic::AtomicBool, Arc},
-    time::{SystemTime, UNIX_EPOCH},
+    convert::{TryFrom, TryInto},
+    fmt,
+    fs::File,
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc, Once},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use borsh::BorshDeserialize;
+use base64::Engine;
+use borsh::{BorshDeserialize, BorshSerialize};
 use bpf_loader_upgradeable::UpgradeableLoaderState;
 use itertools::izip;
 use rand::{prelude::StdRng, rngs::OsRng, SeedableRng};
 use serde::de::DeserializeOwned;
 use sha2::{Digest, Sha256};
+use solana_account_decoder::UiAccountEncoding;
+use solana_address_lookup_table_program::state::{AddressLookupTable, LookupTableMeta};
 use solana_cli_output::display::println_transaction;
-use solana_client::{rpc_client::RpcClient, rpc_config::RpcTransactionConfig};
+use solana_client::{
+    pubsub_client::PubsubClient,
+    rpc_client::RpcClient,
+    rpc_config::{
+        RpcAccountInfoConfig, RpcProgramAccountsConfig, RpcTransactionConfig,
+        RpcTransactionLogsConfig, RpcTransactionLogsFilter,
+    },
+    rpc_filter::{Memcmp, RpcFilterType},
+};
 use solana_program::{
     bpf_loader, bpf_loader_upgradeable,
     hash::Hash,
-    instruction::Instruction,
-    loader_instruction,
-    message::Message,
+    instruction::{AccountMeta, CompiledInstruction, Instruction, InstructionError},
+    loader_instruction, loader_v4,
+    loader_v4_instruction::LoaderV4Instruction,
+    message::{v0::LoadedAddresses, Message, SanitizedMessage, VersionedMessage},
+    nonce,
+    program_error::ProgramError,
     program_option::COption,
-    program_pack::Pack,
+    program_pack::{IsInitialized, Pack},
     pubkey::Pubkey,
-    system_instruction, system_program,
-    sysvar::{self, rent},
+    stake::{
+        self,
+        state::{Authorized, Delegation, Lockup, Meta, Stake, StakeState},
+    },
+    system_instruction::{self, SystemInstruction},
+    system_program,
+    sysvar::{
+        self,
+        rent::{self, Rent},
+        Sysvar,
+    },
+};
+use solana_program_runtime::{
+    compute_budget::ComputeBudget, invoke_context::ProcessInstructionWithContext,
+    timings::ExecuteTimings,
 };
 use solana_runtime::{
     accounts_db::AccountShrinkThreshold,
-    accounts_index::AccountSecondaryIndexes,
+    accounts_index::{AccountSecondaryIndexes, ScanConfig},
     bank::{Bank, TransactionBalancesSet, TransactionExecutionResult, TransactionResults},
     genesis_utils,
     runtime_config::RuntimeConfig,
 };
 use solana_sdk::{
-    account::{Account, AccountSharedData},
+    account::{to_account, Account, AccountSharedData, ReadableAccount, WritableAccount},
+    address_lookup_table_account::AddressLookupTableAccount,
     commitment_config::CommitmentConfig,
-    feature_set,
-    genesis_config::GenesisConfig,
+    compute_budget::ComputeBudgetInstruction,
+    epoch_info::EpochInfo,
+    epoch_schedule::EpochSchedule,
+    feature, feature_set,
+    genesis_config::{ClusterType, GenesisConfig},
     packet,
+    rent_collector::RentCollector,
     signature::Keypair,
-    signature::Signer,
+    signature::{Signature, Signer},
     system_transaction,
-    transaction::{Transaction, VersionedTransaction},
+    transaction::{SanitizedTransaction, Transaction, TransactionError, VersionedTransaction},
+    transaction_context::TransactionReturnData,
 };
 use solana_transaction_status::{
-    ConfirmedTransactionWithStatusMeta, EncodedConfirmedTransactionWithStatusMeta,
-    InnerInstructions, TransactionStatusMeta, TransactionWithStatusMeta, UiTransactionEncoding,
-    VersionedTransactionWithStatusMeta,
+    option_serializer::OptionSerializer, ConfirmedTransactionWithStatusMeta,
+    EncodedConfirmedTransactionWithStatusMeta, InnerInstruction, InnerInstructions,
+    TransactionStatusMeta, TransactionTokenBalance, TransactionWithStatusMeta, UiInnerInstructions,
+    UiInstruction, UiLoadedAddresses, UiTransactionEncoding, UiTransactionReturnData,
+    UiTransactionStatusMeta, UiTransactionTokenBalance, VersionedTransactionWithStatusMeta,
 };
-use spl_associated_token_account::get_associated_token_address;
+use solana_vote_program::vote_state::{self, VoteState};
+use spl_associated_token_account::{
+    get_associated_token_address, get_associated_token_address_with_program_id,
+};
+use spl_token_2022::extension::StateWithExtensions;
+use url::Url;
 
 pub use bincode;
 pub use borsh;
 pub use serde;
 pub use solana_client;
 pub use solana_program;
+pub use solana_program_runtime;
 pub use solana_sdk;
 pub use solana_transaction_status;
 pub use spl_associated_token_account;
 pub use spl_memo;
 pub use spl_token;
+pub use spl_token_2022;
 
 mod keys;
 mod programs;
@@ -74,17 +122,64 @@ pub trait Environment {
     fn execute_transaction<T>(&mut self, txs: T) -> EncodedConfirmedTransactionWithStatusMeta
     where
         VersionedTransaction: From<T>;
+    /// Executes the transactions one after another and returns their execution results in the same order.
+    /// Every transaction pays its fee from its own fee payer, so transactions with different payers can be mixed.
+    fn execute_transactions<T>(
+        &mut self,
+        txs: Vec<T>,
+    ) -> Vec<EncodedConfirmedTransactionWithStatusMeta>
+    where
+        VersionedTransaction: From<T>,
+    {
+        txs.into_iter()
+            .map(|tx| self.execute_transaction(tx))
+            .collect()
+    }
     /// Fetch a recent blockhash, for construction of transactions.
     #[deprecated(since = "0.2.0", note = "Please use `get_latest_blockhash()` instead")]
     fn get_recent_blockhash(&self) -> Hash {
         self.get_latest_blockhash()
     }
+    /// Sends the transaction without waiting for it to be confirmed. Use [Environment::confirm] to wait for the result.
+    fn send_transaction<T>(&mut self, tx: T) -> Signature
+    where
+        VersionedTransaction: From<T>;
+    /// Waits until the transaction with the given signature is confirmed and returns its status.
+    fn confirm(&self, signature: Signature) -> Result<(), TransactionError>;
+    /// Fetch the status of a transaction without waiting for it. None if the transaction is unknown to the environment.
+    fn get_signature_status(&self, signature: Signature) -> Option<Result<(), TransactionError>>;
     /// Fetch the latest blockhash, for construction of transactions.
     fn get_latest_blockhash(&self) -> Hash;
     /// Fetch the amount of lamports needed for an account of the given size to be rent excempt.
     fn get_rent_excemption(&self, data: usize) -> u64;
+    /// Fetch the rent parameters of the environment.
+    fn get_rent(&self) -> Rent;
     /// Fetch an account. None if the account does not exist.
     fn get_account(&self, pubkey: Pubkey) -> Option<Account>;
+    /// Fetch an account at the given commitment level. None if the account does not exist. Local environments always return the current state.
+    fn get_account_with_commitment(
+        &self,
+        pubkey: Pubkey,
+        commitment: CommitmentConfig,
+    ) -> Option<Account>;
+    /// Fetch information about the current epoch.
+    fn get_epoch_info(&self) -> EpochInfo;
+    /// Fetch the current block height, i.e. the number of blocks beneath the current one. Unlike the slot, it does not count skipped slots.
+    fn get_block_height(&self) -> u64;
+    /// Calculate the fee the payer of the message is charged when executing it.
+    fn estimate_fee(&self, message: &Message) -> u64;
+    /// Fetch the minimum amount of lamports a stake account has to delegate, given the active feature set.
+    fn get_minimum_stake_delegation(&self) -> u64;
+    /// Fund the account with the given amount of lamports.
+    /// Remote environments request an airdrop if the balance is below the amount, local environments mint the lamports directly.
+    fn airdrop(&mut self, account: Pubkey, lamports: u64);
+
+    /// Generates a fresh keypair and funds it with the given amount of lamports, see [Environment::airdrop].
+    fn new_funded_account(&mut self, lamports: u64) -> Keypair {
+        let keypair = random_keypair();
+        self.airdrop(keypair.pubkey(), lamports);
+        keypair
+    }
 
     /// Assemble the given instructions into a transaction and sign it. All transactions constructed by this method are signed and payed for by the payer.
     fn tx_with_instructions(
@@ -92,30 +187,12 @@ pub trait Environment {
         instructions: &[Instruction],
         signers: &[&Keypair],
     ) -> Transaction {
-        let payer = self.payer();
-        let mut signer_vec = vec![&payer];
-        signer_vec.extend_from_slice(signers);
-
-        let message = Message::new(instructions, Some(&self.payer().pubkey()));
-        let num_sigs: usize = message.header.num_required_signatures.into();
-        let required_sigs = message.account_keys[..num_sigs]
-            .into_iter()
-            .copied()
-            .collect::<HashSet<_>>();
-        let provided_sigs = signer_vec
-            .iter()
-            .map(|x| x.pubkey())
-            .collect::<HashSet<_>>();
-
-        for key in required_sigs.difference(&provided_sigs) {
-            println!("missing signature from {}", key.to_string());
-        }
-
-        for key in provided_sigs.difference(&required_sigs) {
-            println!("unnecessary signature from {}", key.to_string());
-        }
-
-        Transaction::new(&signer_vec, message, self.get_latest_blockhash())
+        sign_instructions(
+            instructions,
+            &self.payer(),
+            signers,
+            self.get_latest_blockhash(),
+        )
     }
 
     /// Assemble the given instructions into a transaction and sign it. All transactions constructed by this method are signed and payed for by the new_payer.
@@ -125,30 +202,81 @@ pub trait Environment {
         signers: &[&Keypair],
         new_payer: Keypair,
     ) -> Transaction {
-        let payer = new_payer;
+        sign_instructions(
+            instructions,
+            &new_payer,
+            signers,
+            self.get_latest_blockhash(),
+        )
+    }
+
+    /// Signs the already assembled message with the given signers and executes it. The fee payer of the message has to be among the signers.
+    /// Panics if a signature required by the message header is missing, signers not required by the message are ignored.
+    fn sign_and_execute(
+        &mut self,
+        message: Message,
+        signers: &[&Keypair],
+    ) -> EncodedConfirmedTransactionWithStatusMeta {
+        let report = SignerReport::new(&message, signers);
+        assert!(
+            report.missing.is_empty(),
+            "missing signatures from {:?}",
+            report.missing
+        );
+        for key in &report.unnecessary {
+            log::warn!("unnecessary signature from {}", key);
+        }
+
+        let signers = signers
+            .iter()
+            .copied()
+            .filter(|signer| !report.unnecessary.contains(&signer.pubkey()))
+            .collect::<Vec<_>>();
+        let tx = Transaction::new(&signers, message, self.get_latest_blockhash());
+        self.execute_transaction(tx)
+    }
+
+    /// Checks which signatures the given instructions require, assuming the transaction is payed for by the payer.
+    /// Returns the required signers missing from `signers` and the provided signers that are not required.
+    fn validate_signers(&self, instructions: &[Instruction], signers: &[&Keypair]) -> SignerReport {
+        let payer = self.payer();
         let mut signer_vec = vec![&payer];
         signer_vec.extend_from_slice(signers);
 
         let message = Message::new(instructions, Some(&payer.pubkey()));
-        let num_sigs: usize = message.header.num_required_signatures.into();
-        let required_sigs = message.account_keys[..num_sigs]
-            .into_iter()
-            .copied()
-            .collect::<HashSet<_>>();
-        let provided_sigs = signer_vec
-            .iter()
-            .map(|x| x.pubkey())
-            .collect::<HashSet<_>>();
+        SignerReport::new(&message, &signer_vec)
+    }
 
-        for key in required_sigs.difference(&provided_sigs) {
-            println!("missing signature from {}", key.to_string());
-        }
+    /// Whether [Environment::execute_as_transaction] checks that the payer can pay for the transaction before executing it.
+    fn payer_preflight(&self) -> bool {
+        false
+    }
 
-        for key in provided_sigs.difference(&required_sigs) {
-            println!("unnecessary signature from {}", key.to_string());
+    /// Panics with a descriptive message if the fee payer of the message can not cover the fee and the lamports the message transfers out of it via the system program.
+    fn assert_payer_can_pay(&self, message: &Message) {
+        let payer = message.account_keys[0];
+        let needed = self.estimate_fee(message) as u128 + lamports_moved_from(message, payer);
+        let balance = self
+            .get_account(payer)
+            .map_or(0, |account| account.lamports);
+        if (balance as u128) < needed {
+            panic!("payer {} has {} lamports, needs {}", payer, balance, needed);
         }
+    }
 
-        Transaction::new(&signer_vec, message, self.get_latest_blockhash())
+    /// Packs the given instructions greedily into as few transactions as possible, keeping their order and the packet size limit.
+    /// All transactions are signed and payed for by the payer, each signer only signs the transactions requiring it.
+    fn batch_instructions(
+        &self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+    ) -> Vec<Transaction> {
+        let payer = self.payer();
+        let mut batcher = TransactionBatcher::new(&payer, signers, self.get_latest_blockhash());
+        for instruction in instructions {
+            batcher.add(instruction.clone());
+        }
+        batcher.finish()
     }
 
     /// Assemble the given instructions into a transaction and sign it. All transactions executed by this method are signed and payed for by the payer.
@@ -158,6 +286,9 @@ pub trait Environment {
         signers: &[&Keypair],
     ) -> EncodedConfirmedTransactionWithStatusMeta {
         let tx = self.tx_with_instructions(instructions, signers);
+        if self.payer_preflight() {
+            self.assert_payer_can_pay(&tx.message);
+        }
         return self.execute_transaction(tx);
     }
 
@@ -172,6 +303,62 @@ pub trait Environment {
         return self.execute_transaction(tx);
     }
 
+    /// Assemble the given instructions into a transaction and sign it. All transactions executed by this method are signed and payed for by the payer.
+    /// Prepends compute budget instructions requesting the given compute unit limit and, optionally, price in micro-lamports.
+    fn execute_as_transaction_with_budget(
+        &mut self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+        cu_limit: u32,
+        cu_price: Option<u64>,
+    ) -> EncodedConfirmedTransactionWithStatusMeta {
+        let mut budget_instructions =
+            vec![ComputeBudgetInstruction::set_compute_unit_limit(cu_limit)];
+        if let Some(cu_price) = cu_price {
+            budget_instructions.push(ComputeBudgetInstruction::set_compute_unit_price(cu_price));
+        }
+        budget_instructions.extend_from_slice(instructions);
+        self.execute_as_transaction(&budget_instructions, signers)
+    }
+
+    /// Assemble the given instructions into a transaction and sign it. All transactions executed by this method are signed and payed for by the payer.
+    /// Checks the invariant on the resulting state afterwards and panics, printing the transaction, if it does not hold.
+    fn execute_checked<F>(
+        &mut self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+        invariant: F,
+    ) -> EncodedConfirmedTransactionWithStatusMeta
+    where
+        F: Fn(&Self) -> bool,
+    {
+        let tx = self.execute_as_transaction(instructions, signers);
+        if !invariant(self) {
+            tx.print();
+            panic!("invariant violated by transaction");
+        }
+        tx
+    }
+
+    /// Assemble the given instructions into a transaction and sign it. All transactions executed by this method are signed and payed for by the payer.
+    /// Returns the execution results as plain Rust types, for easy assertions.
+    fn execute_as_transaction_structured(
+        &mut self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+    ) -> ExecutionOutcome {
+        ExecutionOutcome::from(&self.execute_as_transaction(instructions, signers))
+    }
+
+    /// Executes a single instruction in its own transaction, signed and payed for by the payer, and returns the execution results.
+    fn process_instruction(
+        &mut self,
+        instruction: Instruction,
+        signers: &[&Keypair],
+    ) -> ExecutionOutcome {
+        self.execute_as_transaction_structured(&[instruction], signers)
+    }
+
     /// Assemble the given instructions into a transaction and sign it. All transactions executed by this method are signed and payed for by the payer.
     /// Prints the transaction before sending it.
     fn execute_as_transaction_debug(
@@ -296,31 +483,159 @@ pub trait Environment {
         .assert_success();
     }
 
+    /// Executes as few transactions as possible constructing token accounts for the given mints, like [Environment::create_token_account].
+    /// Returns the addresses of the new token accounts.
+    fn create_token_accounts_batch(&mut self, specs: &[(Keypair, Pubkey)]) -> Vec<Pubkey> {
+        let rent = self.get_rent_excemption(spl_token::state::Account::LEN);
+        let payer = self.payer().pubkey();
+        let mut instructions = vec![];
+        for (account, mint) in specs {
+            instructions.push(system_instruction::create_account(
+                &payer,
+                &account.pubkey(),
+                rent,
+                spl_token::state::Account::LEN as u64,
+                &spl_token::ID,
+            ));
+            instructions.push(
+                spl_token::instruction::initialize_account(
+                    &spl_token::ID,
+                    &account.pubkey(),
+                    mint,
+                    &account.pubkey(),
+                )
+                .unwrap(),
+            );
+        }
+
+        let signers = specs.iter().map(|(account, _)| account).collect::<Vec<_>>();
+        let txs = self.batch_instructions(&instructions, &signers);
+        for result in self.execute_transactions(txs) {
+            result.assert_success();
+        }
+        specs.iter().map(|(account, _)| account.pubkey()).collect()
+    }
+
     /// Executes a transaction constructing the associated token account of the specified mint belonging to the owner. This will fail if the account already exists.
+    /// The token program (spl-token or token-2022) is detected from the owner of the mint.
     fn create_associated_token_account(&mut self, owner: &Keypair, mint: Pubkey) -> Pubkey {
+        let token_program = token_program_of_mint(self.get_account(mint));
+        self.create_associated_token_account_for(owner, mint, token_program)
+    }
+
+    /// Executes a transaction constructing the associated token account of the specified mint belonging to the owner, using the given token program. This will fail if the account already exists.
+    fn create_associated_token_account_for(
+        &mut self,
+        owner: &Keypair,
+        mint: Pubkey,
+        token_program: Pubkey,
+    ) -> Pubkey {
         self.execute_as_transaction(
             &[
                 spl_associated_token_account::instruction::create_associated_token_account(
                     &self.payer().pubkey(),
                     &owner.pubkey(),
                     &mint,
-                    &spl_token::ID,
+                    &token_program,
                 ),
             ],
             &[],
         );
-        get_associated_token_address(&owner.pubkey(), &mint)
+        get_associated_token_address_with_program_id(&owner.pubkey(), &mint, &token_program)
+    }
+
+    /// Executes transactions creating a new mint with the payer as mint authority, creating the associated token account of the recipient and minting the amount into it.
+    /// Returns the mint keypair and the funded associated token account.
+    fn setup_token(&mut self, decimals: u8, recipient: &Keypair, amount: u64) -> (Keypair, Pubkey) {
+        let mint = random_keypair();
+        let payer = self.payer();
+        self.create_token_mint(&mint, payer.pubkey(), None, decimals);
+        let ata = self.create_associated_token_account(recipient, mint.pubkey());
+        self.mint_tokens(mint.pubkey(), &payer, ata, amount);
+        (mint, ata)
     }
 
     /// Executes a transaction constructing the associated token account of the specified mint belonging to the owner.
+    /// The token program (spl-token or token-2022) is detected from the owner of the mint.
     fn get_or_create_associated_token_account(&mut self, owner: &Keypair, mint: Pubkey) -> Pubkey {
-        let acc = get_associated_token_address(&owner.pubkey(), &mint);
+        let token_program = token_program_of_mint(self.get_account(mint));
+        let acc = self.associated_token_address(owner.pubkey(), mint);
         if self.get_account(acc).is_none() {
-            self.create_associated_token_account(owner, mint);
+            self.create_associated_token_account_for(owner, mint, token_program);
         }
         acc
     }
 
+    /// Derives the associated token account address of the specified mint belonging to the owner, without creating it.
+    /// The token program (spl-token or token-2022) is detected from the owner of the mint.
+    fn associated_token_address(&self, owner: Pubkey, mint: Pubkey) -> Pubkey {
+        let token_program = token_program_of_mint(self.get_account(mint));
+        get_associated_token_address_with_program_id(&owner, &mint, &token_program)
+    }
+
+    /// Executes transactions creating the wrapped SOL associated token account of the owner and funding it with the given amount of lamports.
+    fn create_wrapped_sol_account(&mut self, owner: &Keypair, lamports: u64) -> Pubkey {
+        let account =
+            self.get_or_create_associated_token_account(owner, spl_token::native_mint::ID);
+        self.execute_as_transaction(
+            &[
+                system_instruction::transfer(&self.payer().pubkey(), &account, lamports),
+                spl_token::instruction::sync_native(&spl_token::ID, &account).unwrap(),
+            ],
+            &[],
+        )
+        .assert_success();
+        account
+    }
+
+    /// Executes a transaction constructing a rent-excempt durable nonce account with the given authority.
+    fn create_nonce_account(&mut self, nonce: &Keypair, authority: Pubkey) -> Pubkey {
+        self.execute_as_transaction(
+            &system_instruction::create_nonce_account(
+                &self.payer().pubkey(),
+                &nonce.pubkey(),
+                &authority,
+                self.get_rent_excemption(nonce::State::size()),
+            ),
+            &[nonce],
+        )
+        .assert_success();
+        nonce.pubkey()
+    }
+
+    /// Assemble the given instructions into a transaction using the durable nonce instead of a recent blockhash, sign it and execute it.
+    /// The nonce is advanced by the first instruction of the transaction. The transaction is payed for by the payer.
+    fn execute_with_nonce(
+        &mut self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+        nonce_account: Pubkey,
+        nonce_authority: &Keypair,
+    ) -> EncodedConfirmedTransactionWithStatusMeta {
+        let nonce_hash = match self
+            .get_serde_deserialized_account::<nonce::state::Versions>(nonce_account)
+            .expect("nonce account does not exist")
+            .state()
+        {
+            nonce::State::Initialized(data) => data.blockhash(),
+            nonce::State::Uninitialized => panic!("nonce account is not initialized"),
+        };
+
+        let payer = self.payer();
+        let message = Message::new_with_nonce(
+            instructions.to_vec(),
+            Some(&payer.pubkey()),
+            &nonce_account,
+            &nonce_authority.pubkey(),
+        );
+        let mut signer_vec = vec![&payer, nonce_authority];
+        signer_vec.extend_from_slice(signers);
+        signer_vec.sort_by_key(|signer| signer.pubkey());
+        signer_vec.dedup_by_key(|signer| signer.pubkey());
+
+        self.execute_transaction(Transaction::new(&signer_vec, message, nonce_hash))
+    }
+
     /// Executes a transaction creating and filling the given account with the given data.
     /// The account is required to be empty and will be owned by bpf_loader afterwards.
     fn create_account_with_data(&mut self, account: &Keypair, data: Vec<u8>) {
@@ -355,109 +670,1312 @@ pub trait Environment {
     /// The keypair is derived from the file contents.
     fn deploy_program<P: AsRef<Path>>(&mut self, program_path: P) -> Pubkey {
         let data = std::fs::read(program_path).unwrap();
+        self.deploy_program_from_bytes(&data)
+    }
+
+    /// Executes a transaction deploying a program from the given ELF bytes if it does not already exist.
+    /// The keypair is derived from the bytes.
+    fn deploy_program_from_bytes(&mut self, data: &[u8]) -> Pubkey {
         let mut hash = Sha256::default();
-        hash.update(&data);
+        hash.update(data);
         let mut rng = StdRng::from_seed(hash.finalize()[..].try_into().unwrap());
         let keypair = Keypair::generate(&mut rng);
+        self.deploy_program_from_bytes_at(data, &keypair)
+    }
 
-        if self.get_account(keypair.pubkey()).is_none() {
-            self.create_account_with_data(&keypair, data);
+    /// Executes a transaction deploying a program from a file to the address of the given keypair if it does not already exist.
+    /// Keeps the program id stable when the bytecode changes.
+    fn deploy_program_at<P: AsRef<Path>>(
+        &mut self,
+        program_path: P,
+        program_id: &Keypair,
+    ) -> Pubkey {
+        let data = std::fs::read(program_path).unwrap();
+        self.deploy_program_from_bytes_at(&data, program_id)
+    }
+
+    /// Executes a transaction deploying a program from the given ELF bytes to the address of the given keypair if it does not already exist.
+    fn deploy_program_from_bytes_at(&mut self, data: &[u8], program_id: &Keypair) -> Pubkey {
+        if self.get_account(program_id.pubkey()).is_none() {
+            self.create_account_with_data(program_id, data.to_vec());
             self.execute_as_transaction(
                 &[loader_instruction::finalize(
-                    &keypair.pubkey(),
+                    &program_id.pubkey(),
                     &bpf_loader::id(),
                 )],
-                &[&keypair],
+                &[program_id],
             )
             .assert_success();
         }
 
-        keypair.pubkey()
+        program_id.pubkey()
     }
 
-    /// Gets and unpacks an account. None if the account does not exist.
-    fn get_unpacked_account<T: Pack>(&self, pubkey: Pubkey) -> Option<T> {
-        let acc = self.get_account(pubkey)?;
-        Some(T::unpack_unchecked(&acc.data).unwrap())
+    /// Executes transactions deploying an upgradeable program from a file, with the given upgrade authority.
+    /// The program data account has room for twice the size of the program, so it can be upgraded to a larger binary.
+    fn deploy_upgradeable_program<P: AsRef<Path>>(
+        &mut self,
+        program_path: P,
+        authority: &Keypair,
+    ) -> Pubkey {
+        let len = std::fs::metadata(program_path.as_ref()).unwrap().len() as usize;
+        self.deploy_upgradeable_program_with_max_len(program_path, authority, 2 * len)
     }
 
-    /// Gets and deserializes an account. None if the account does not exist.
-    fn get_deserialized_account<T: BorshDeserialize>(&self, pubkey: Pubkey) -> Option<T> {
-        let acc = self.get_account(pubkey)?;
-        Some(T::try_from_slice(&acc.data).unwrap())
-    }
+    /// Executes transactions deploying an upgradeable program from a file, with the given upgrade authority.
+    /// The program data account has room for programs of up to `max_data_len` bytes.
+    fn deploy_upgradeable_program_with_max_len<P: AsRef<Path>>(
+        &mut self,
+        program_path: P,
+        authority: &Keypair,
+        max_data_len: usize,
+    ) -> Pubkey {
+        let data = std::fs::read(program_path).unwrap();
+        let buffer = random_keypair();
+        let program = random_keypair();
+        let payer = self.payer().pubkey();
 
-    /// Gets and deserializes an account. None if the account does not exist.
-    fn get_serde_deserialized_account<'a, T: DeserializeOwned>(&self, pubkey: Pubkey) -> Option<T> {
-        let acc = self.get_account(pubkey)?;
-        Some(bincode::deserialize(&acc.data).unwrap())
-    }
-}
+        self.execute_as_transaction(
+            &bpf_loader_upgradeable::create_buffer(
+                &payer,
+                &buffer.pubkey(),
+                &authority.pubkey(),
+                self.get_rent_excemption(UpgradeableLoaderState::size_of_buffer(data.len())),
+                data.len(),
+            )
+            .unwrap(),
+            &[&buffer],
+        )
+        .assert_success();
 
-/// An clean environment that executes transactions locally. Good for testing and debugging.
-/// This environment has the most important SPL programs: spl-token, spl-associated-token-account and spl-memo v1 and v3.
-pub struct LocalEnvironment {
-    bank: Bank,
-    faucet: Keypair,
-}
+        let mut offset = 0usize;
+        for chunk in data.chunks(900) {
+            println!("writing bytes {} to {}", offset, offset + chunk.len());
+            self.execute_as_transaction(
+                &[bpf_loader_upgradeable::write(
+                    &buffer.pubkey(),
+                    &authority.pubkey(),
+                    offset as u32,
+                    chunk.to_vec(),
+                )],
+                &[authority],
+            )
+            .assert_success();
+            offset += chunk.len();
+        }
 
-impl LocalEnvironment {
-    /// Constructs a builder for a local environment
-    pub fn builder() -> LocalEnvironmentBuilder {
-        LocalEnvironmentBuilder::new()
-    }
+        self.execute_as_transaction(
+            &bpf_loader_upgradeable::deploy_with_max_program_len(
+                &payer,
+                &program.pubkey(),
+                &buffer.pubkey(),
+                &authority.pubkey(),
+                self.get_rent_excemption(UpgradeableLoaderState::size_of_program()),
+                max_data_len,
+            )
+            .unwrap(),
+            &[&program, authority],
+        )
+        .assert_success();
 
-    /// Constructs a clean local environment.
-    pub fn new() -> LocalEnvironment {
-        Self::builder().build()
+        program.pubkey()
     }
 
-    pub fn bank(&mut self) -> &mut Bank {
-        &mut self.bank
-    }
+    /// Executes transactions deploying a program from the given ELF bytes using the loader-v4 instruction set, with the given upgrade authority.
+    /// Panics if the loader-v4 feature is not active in the environment.
+    fn deploy_loader_v4_program(&mut self, elf: &[u8], authority: &Keypair) -> Pubkey {
+        let feature_active = self
+            .get_account(feature_set::enable_program_runtime_v2_and_loader_v4::id())
+            .and_then(|account| feature::from_account(&account))
+            .map_or(false, |feature| feature.activated_at.is_some());
+        assert!(
+            feature_active,
+            "loader-v4 is not active in this environment"
+        );
 
-    /// Advance the bank to the next blockhash.
-    pub fn advance_blockhash(&self) -> Hash {
-        let parent_distance = if self.bank.slot() == 0 {
-            1
-        } else {
-            self.bank.slot() - self.bank.parent_slot()
-        };
+        let program = random_keypair();
+        self.execute_transaction(system_transaction::create_account(
+            &self.payer(),
+            &program,
+            self.get_latest_blockhash(),
+            0,
+            0,
+            &loader_v4::id(),
+        ))
+        .assert_success();
 
-        for _ in 0..parent_distance {
-            let last_blockhash = self.bank.last_blockhash();
-            while self.bank.last_blockhash() == last_blockhash {
-                self.bank.register_tick(&Hash::new_unique())
-            }
+        let payer = self.payer().pubkey();
+        let mut offset = 0usize;
+        for chunk in elf.chunks(900) {
+            println!("writing bytes {} to {}", offset, offset + chunk.len());
+            self.execute_as_transaction(
+                &[Instruction::new_with_bincode(
+                    loader_v4::id(),
+                    &LoaderV4Instruction::Write {
+                        offset: offset as u32,
+                        bytes: chunk.to_vec(),
+                    },
+                    vec![
+                        AccountMeta::new(program.pubkey(), false),
+                        AccountMeta::new_readonly(authority.pubkey(), true),
+                        AccountMeta::new(payer, true),
+                    ],
+                )],
+                &[authority],
+            )
+            .assert_success();
+            offset += chunk.len();
         }
 
-        self.get_latest_blockhash()
+        self.execute_as_transaction(
+            &[Instruction::new_with_bincode(
+                loader_v4::id(),
+                &LoaderV4Instruction::Deploy,
+                vec![
+                    AccountMeta::new(program.pubkey(), false),
+                    AccountMeta::new_readonly(authority.pubkey(), true),
+                ],
+            )],
+            &[authority],
+        )
+        .assert_success();
+
+        program.pubkey()
     }
-}
 
-impl Environment for LocalEnvironment {
-    fn payer(&self) -> Keypair {
-        clone_keypair(&self.faucet)
+    /// Fetch the owner of an account. None if the account does not exist.
+    fn get_account_owner(&self, pubkey: Pubkey) -> Option<Pubkey> {
+        self.get_account(pubkey).map(|account| account.owner)
     }
 
-    fn execute_transaction<T>(&mut self, tx: T) -> EncodedConfirmedTransactionWithStatusMeta
+    /// Fetch the decimals of a spl-token or token-2022 mint. None if the account does not exist or is not a mint.
+    fn get_mint_decimals(&self, mint: Pubkey) -> Option<u8> {
+        unpack_mint(&self.get_account(mint)?).map(|mint| mint.decimals)
+    }
+
+    /// Fetch the total supply of a spl-token or token-2022 mint. None if the account does not exist or is not a mint.
+    fn get_token_supply(&self, mint: Pubkey) -> Option<u64> {
+        unpack_mint(&self.get_account(mint)?).map(|mint| mint.supply)
+    }
+
+    /// Gets and unpacks a spl-token or token-2022 account. None if the account does not exist or is not a token account.
+    fn get_token_account_info(&self, account: Pubkey) -> Option<TokenAccountInfo> {
+        let acc = self.get_account(account)?;
+        if acc.owner == spl_token_2022::ID {
+            let token_account =
+                StateWithExtensions::<spl_token_2022::state::Account>::unpack(&acc.data)
+                    .ok()?
+                    .base;
+            Some(TokenAccountInfo {
+                owner: token_account.owner,
+                mint: token_account.mint,
+                amount: token_account.amount,
+                delegate: token_account.delegate.into(),
+                state: match token_account.state {
+                    spl_token_2022::state::AccountState::Uninitialized => {
+                        spl_token::state::AccountState::Uninitialized
+                    }
+                    spl_token_2022::state::AccountState::Initialized => {
+                        spl_token::state::AccountState::Initialized
+                    }
+                    spl_token_2022::state::AccountState::Frozen => {
+                        spl_token::state::AccountState::Frozen
+                    }
+                },
+            })
+        } else if acc.owner == spl_token::ID {
+            let token_account = spl_token::state::Account::unpack(&acc.data).ok()?;
+            Some(TokenAccountInfo {
+                owner: token_account.owner,
+                mint: token_account.mint,
+                amount: token_account.amount,
+                delegate: token_account.delegate.into(),
+                state: token_account.state,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Gets an account and decodes its data using the given decoder. None if the account does not exist.
+    fn get_account_data_as<T, E, F>(
+        &self,
+        pubkey: Pubkey,
+        decode: F,
+    ) -> Result<Option<T>, DecodeError>
     where
-        VersionedTransaction: From<T>,
+        E: fmt::Display,
+        F: FnOnce(&[u8]) -> Result<T, E>,
     {
-        let tx = tx.into();
-        let len = bincode::serialize(&tx).unwrap().len();
-        if len > packet::PACKET_DATA_SIZE {
-            panic!(
-                "tx {:?} of size {} is {} too large",
-                tx,
-                len,
-                len - packet::PACKET_DATA_SIZE
-            )
+        match self.get_account(pubkey) {
+            Some(acc) => decode(&acc.data).map(Some).map_err(|err| DecodeError {
+                pubkey,
+                message: err.to_string(),
+            }),
+            None => Ok(None),
         }
-        let txs = vec![tx];
-
-        let batch = self.bank.prepare_entry_batch(txs.clone()).unwrap();
-        let tx_sanitized = batch.sanitized_transactions()[0].clone();
+    }
+
+    /// Gets and unpacks an account. None if the account does not exist.
+    /// Panics if the account data can't be unpacked.
+    fn get_unpacked_account<T: Pack>(&self, pubkey: Pubkey) -> Option<T> {
+        self.get_account_data_as(pubkey, T::unpack_unchecked)
+            .unwrap()
+    }
+
+    /// Gets and unpacks an account, checking its size and that it is initialized. None if the account does not exist.
+    fn try_get_unpacked_account<T: Pack + IsInitialized>(
+        &self,
+        pubkey: Pubkey,
+    ) -> Result<Option<T>, ProgramError> {
+        match self.get_account(pubkey) {
+            Some(acc) => T::unpack(&acc.data).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets and deserializes an account. None if the account does not exist.
+    /// Panics if the account data can't be deserialized.
+    fn get_deserialized_account<T: BorshDeserialize>(&self, pubkey: Pubkey) -> Option<T> {
+        self.get_account_data_as(pubkey, T::try_from_slice).unwrap()
+    }
+
+    /// Gets and deserializes an account. None if the account does not exist.
+    /// Panics if the account data can't be deserialized.
+    fn get_serde_deserialized_account<'a, T: DeserializeOwned>(&self, pubkey: Pubkey) -> Option<T> {
+        self.get_account_data_as(pubkey, |data| bincode::deserialize(data))
+            .unwrap()
+    }
+
+    /// Formats owner, lamports and a hexdump of the data of an account. None if the account does not exist.
+    fn account_hex(&self, pubkey: Pubkey) -> Option<String> {
+        let acc = self.get_account(pubkey)?;
+        let mut out = format!(
+            "account {} owner: {} lamports: {} data: {} bytes\n",
+            pubkey,
+            acc.owner,
+            acc.lamports,
+            acc.data.len()
+        );
+        for (i, line) in acc.data.chunks(16).enumerate() {
+            let hex = line
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = line
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+            out += &format!("{:08x}  {:<47}  |{}|\n", i * 16, hex, ascii);
+        }
+        Some(out)
+    }
+
+    /// Prints owner, lamports and a hexdump of the data of an account.
+    fn dump_account_hex(&self, pubkey: Pubkey) {
+        match self.account_hex(pubkey) {
+            Some(hex) => print!("{}", hex),
+            None => println!("account {} does not exist", pubkey),
+        }
+    }
+
+    /// Panics if the account does not exist.
+    fn assert_account_exists(&self, pubkey: Pubkey) {
+        if self.get_account(pubkey).is_none() {
+            panic!("account {} does not exist", pubkey);
+        }
+    }
+
+    /// Panics if the account exists.
+    fn assert_account_absent(&self, pubkey: Pubkey) {
+        if let Some(acc) = self.get_account(pubkey) {
+            panic!(
+                "account {} exists (owner: {}, lamports: {})",
+                pubkey, acc.owner, acc.lamports
+            );
+        }
+    }
+
+    /// Panics if the account does not exist or is not owned by the expected program.
+    fn assert_owner(&self, pubkey: Pubkey, expected_owner: Pubkey) {
+        let acc = self
+            .get_account(pubkey)
+            .unwrap_or_else(|| panic!("account {} does not exist", pubkey));
+        if acc.owner != expected_owner {
+            panic!(
+                "account {} is owned by {}, expected {}",
+                pubkey, acc.owner, expected_owner
+            );
+        }
+    }
+
+    /// Panics if the account does not exist or does not hold exactly the expected amount of lamports.
+    fn assert_lamports(&self, pubkey: Pubkey, expected: u64) {
+        let acc = self
+            .get_account(pubkey)
+            .unwrap_or_else(|| panic!("account {} does not exist", pubkey));
+        if acc.lamports != expected {
+            panic!(
+                "account {} has {} lamports, expected {}",
+                pubkey, acc.lamports, expected
+            );
+        }
+    }
+
+    /// Panics if the account does not exist or its data does not have the expected length.
+    fn assert_data_len(&self, pubkey: Pubkey, len: usize) {
+        let acc = self
+            .get_account(pubkey)
+            .unwrap_or_else(|| panic!("account {} does not exist", pubkey));
+        if acc.data.len() != len {
+            panic!(
+                "account {} has {} bytes of data, expected {}",
+                pubkey,
+                acc.data.len(),
+                len
+            );
+        }
+    }
+
+    /// Panics if the account is not the associated token account of the mint belonging to the owner.
+    /// The token program (spl-token or token-2022) is detected from the owner of the mint.
+    fn assert_is_associated_token_account(&self, account: Pubkey, owner: Pubkey, mint: Pubkey) {
+        let expected = self.associated_token_address(owner, mint);
+        if account != expected {
+            panic!(
+                "account {} is not the associated token account of owner {} and mint {}, expected {}",
+                account, owner, mint, expected
+            );
+        }
+    }
+}
+
+/// Packs instructions one at a time into transactions that fit into a single packet.
+pub struct TransactionBatcher<'a> {
+    payer: &'a Keypair,
+    signers: Vec<&'a Keypair>,
+    blockhash: Hash,
+    current: Vec<Instruction>,
+    transactions: Vec<Transaction>,
+}
+
+impl<'a> TransactionBatcher<'a> {
+    /// Constructs an empty batcher. All transactions are payed for by the payer and signed by the required subset of the signers.
+    pub fn new(payer: &'a Keypair, signers: &[&'a Keypair], blockhash: Hash) -> Self {
+        TransactionBatcher {
+            payer,
+            signers: signers.to_vec(),
+            blockhash,
+            current: vec![],
+            transactions: vec![],
+        }
+    }
+
+    /// Appends the instruction to the current transaction, or starts a new transaction if it would not fit anymore.
+    /// Panics if the instruction does not fit into a transaction on its own.
+    pub fn add(&mut self, instruction: Instruction) -> &mut Self {
+        self.current.push(instruction);
+        if self.fits(&self.current) {
+            return self;
+        }
+
+        let instruction = self.current.pop().unwrap();
+        self.flush();
+        assert!(
+            self.fits(&[instruction.clone()]),
+            "instruction for program {} does not fit into a single transaction",
+            instruction.program_id
+        );
+        self.current.push(instruction);
+        self
+    }
+
+    /// Signs the pending instructions and returns all transactions.
+    pub fn finish(mut self) -> Vec<Transaction> {
+        self.flush();
+        self.transactions
+    }
+
+    fn fits(&self, instructions: &[Instruction]) -> bool {
+        let message = Message::new(instructions, Some(&self.payer.pubkey()));
+        let size = bincode::serialized_size(&Transaction::new_unsigned(message)).unwrap();
+        size as usize <= packet::PACKET_DATA_SIZE
+    }
+
+    fn flush(&mut self) {
+        if self.current.is_empty() {
+            return;
+        }
+        let instructions = std::mem::take(&mut self.current);
+        let message = Message::new(&instructions, Some(&self.payer.pubkey()));
+        let num_sigs: usize = message.header.num_required_signatures.into();
+        let required_sigs = &message.account_keys[..num_sigs];
+        let mut signers = vec![self.payer];
+        signers.extend(
+            self.signers
+                .iter()
+                .copied()
+                .filter(|signer| required_sigs.contains(&signer.pubkey())),
+        );
+        self.transactions
+            .push(Transaction::new(&signers, message, self.blockhash));
+    }
+}
+
+/// Result of checking the provided signers of a transaction against the signatures its message requires.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SignerReport {
+    /// Signers required by the message, but not provided.
+    pub missing: Vec<Pubkey>,
+    /// Signers provided, but not required by the message.
+    pub unnecessary: Vec<Pubkey>,
+}
+
+impl SignerReport {
+    fn new(message: &Message, signers: &[&Keypair]) -> Self {
+        let num_sigs: usize = message.header.num_required_signatures.into();
+        let required_sigs = message.account_keys[..num_sigs]
+            .into_iter()
+            .copied()
+            .collect::<HashSet<_>>();
+        let provided_sigs = signers.iter().map(|x| x.pubkey()).collect::<HashSet<_>>();
+
+        let mut missing = required_sigs
+            .difference(&provided_sigs)
+            .copied()
+            .collect::<Vec<_>>();
+        let mut unnecessary = provided_sigs
+            .difference(&required_sigs)
+            .copied()
+            .collect::<Vec<_>>();
+        missing.sort();
+        unnecessary.sort();
+        SignerReport {
+            missing,
+            unnecessary,
+        }
+    }
+
+    /// Returns true if no signers are missing or unnecessary.
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.unnecessary.is_empty()
+    }
+
+    fn warn(&self) {
+        for key in &self.missing {
+            log::warn!("missing signature from {}", key);
+        }
+        for key in &self.unnecessary {
+            log::warn!("unnecessary signature from {}", key);
+        }
+    }
+}
+
+/// Assembles the instructions into a transaction payed for by the payer and signs it, warning about missing and unnecessary signers.
+fn sign_instructions(
+    instructions: &[Instruction],
+    payer: &Keypair,
+    signers: &[&Keypair],
+    recent_blockhash: Hash,
+) -> Transaction {
+    let mut signer_vec = vec![payer];
+    signer_vec.extend_from_slice(signers);
+
+    let message = Message::new(instructions, Some(&payer.pubkey()));
+    SignerReport::new(&message, &signer_vec).warn();
+    Transaction::new(&signer_vec, message, recent_blockhash)
+}
+
+/// The most important fields of a token account, independent of the token program owning it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenAccountInfo {
+    pub owner: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub delegate: Option<Pubkey>,
+    pub state: spl_token::state::AccountState,
+}
+
+/// Execution settings of a [LocalEnvironment], configured through its builder and carried over by [LocalEnvironment::fork].
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    compute_budget: Option<ComputeBudget>,
+    enforce_blockhash_expiry: bool,
+    log_messages_bytes_limit: Option<usize>,
+    verify_signatures: bool,
+    collect_rent_on_warp: bool,
+    collect_timings: bool,
+    payer_preflight: bool,
+    max_tx_size: usize,
+    dry_run: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            compute_budget: None,
+            enforce_blockhash_expiry: false,
+            log_messages_bytes_limit: None,
+            verify_signatures: true,
+            collect_rent_on_warp: true,
+            collect_timings: false,
+            payer_preflight: true,
+            max_tx_size: packet::PACKET_DATA_SIZE,
+            dry_run: false,
+        }
+    }
+}
+
+/// The settings written by [LocalEnvironment::save_state]. The compute budget is not serializable and therefore left out.
+type SavedSettings = (bool, Option<usize>, bool, bool, bool, bool, usize, bool);
+
+impl Settings {
+    fn to_saved(self) -> SavedSettings {
+        (
+            self.enforce_blockhash_expiry,
+            self.log_messages_bytes_limit,
+            self.verify_signatures,
+            self.collect_rent_on_warp,
+            self.collect_timings,
+            self.payer_preflight,
+            self.max_tx_size,
+            self.dry_run,
+        )
+    }
+
+    fn from_saved(saved: SavedSettings) -> Self {
+        let (
+            enforce_blockhash_expiry,
+            log_messages_bytes_limit,
+            verify_signatures,
+            collect_rent_on_warp,
+            collect_timings,
+            payer_preflight,
+            max_tx_size,
+            dry_run,
+        ) = saved;
+        Settings {
+            compute_budget: None,
+            enforce_blockhash_expiry,
+            log_messages_bytes_limit,
+            verify_signatures,
+            collect_rent_on_warp,
+            collect_timings,
+            payer_preflight,
+            max_tx_size,
+            dry_run,
+        }
+    }
+}
+
+/// An clean environment that executes transactions locally. Good for testing and debugging.
+/// This environment has the most important SPL programs: spl-token, spl-associated-token-account and spl-memo v1 and v3.
+pub struct LocalEnvironment {
+    bank: Bank,
+    faucet: Keypair,
+    genesis_config: GenesisConfig,
+    builtins: Vec<(String, Pubkey, ProcessInstructionWithContext)>,
+    settings: Settings,
+    last_logs: Vec<String>,
+    signature_statuses: HashMap<Signature, Result<(), TransactionError>>,
+    refuel_lamports: Option<u64>,
+    last_timings: Option<ExecuteTimings>,
+    recorded_transactions: Vec<VersionedTransaction>,
+}
+
+impl LocalEnvironment {
+    /// Constructs a builder for a local environment
+    pub fn builder() -> LocalEnvironmentBuilder {
+        LocalEnvironmentBuilder::new()
+    }
+
+    /// Constructs a clean local environment.
+    pub fn new() -> LocalEnvironment {
+        Self::builder().build()
+    }
+
+    /// Constructs a local environment from the account and program lists of a `solana-program-test` `ProgramTest` setup.
+    pub fn from_program_test_accounts(
+        accounts: Vec<(Pubkey, Account)>,
+        programs: Vec<(Pubkey, PathBuf)>,
+    ) -> LocalEnvironment {
+        Self::builder()
+            .add_program_test_accounts(accounts, programs)
+            .build()
+    }
+
+    /// Constructs a clean local environment, using the given keypair as faucet.
+    pub fn new_with_faucet(faucet: Keypair) -> LocalEnvironment {
+        Self::builder().set_faucet_keypair(faucet).build()
+    }
+
+    pub fn bank(&mut self) -> &mut Bank {
+        &mut self.bank
+    }
+
+    /// Advance the bank to the next blockhash.
+    /// The slot stays the same, use [LocalEnvironment::advance_slots] to move to the next slot together with the blockhash.
+    pub fn advance_blockhash(&self) -> Hash {
+        let parent_distance = if self.bank.slot() == 0 {
+            1
+        } else {
+            self.bank.slot() - self.bank.parent_slot()
+        };
+
+        for _ in 0..parent_distance {
+            self.register_blockhash();
+        }
+
+        self.get_latest_blockhash()
+    }
+
+    /// Registers ticks until the bank completes a block and registers a new blockhash.
+    fn register_blockhash(&self) {
+        let last_blockhash = self.bank.last_blockhash();
+        while self.bank.last_blockhash() == last_blockhash {
+            self.bank.register_tick(&Hash::new_unique())
+        }
+    }
+
+    /// Registers the given hash as the latest blockhash, ticking up to the next block boundary first.
+    fn register_blockhash_as(&self, blockhash: &Hash) {
+        while !self.bank.is_block_boundary(self.bank.tick_height() + 1) {
+            self.bank.register_tick(&Hash::new_unique());
+        }
+        self.bank.register_tick(blockhash);
+    }
+
+    /// Returns the rent collector of the current bank, which holds the rent parameters together with the epoch schedule.
+    pub fn rent_collector(&self) -> RentCollector {
+        self.bank.rent_collector().clone()
+    }
+
+    /// Moves the environment to the given future slot, creating a new bank on top of the current one.
+    /// Sysvars like the clock are updated accordingly. Unless disabled with [LocalEnvironmentBuilder::set_collect_rent_on_warp],
+    /// rent is collected from all accounts for the new epoch.
+    pub fn warp_to_slot(self, slot: Slot) -> LocalEnvironment {
+        assert!(
+            slot > self.bank.slot(),
+            "cannot warp from slot {} to slot {}",
+            self.bank.slot(),
+            slot
+        );
+        let env = self.new_child_bank(slot);
+        if env.settings.collect_rent_on_warp {
+            env.collect_rent();
+        }
+        // a single blockhash for the new slot, advance_blockhash would register one per skipped slot
+        env.register_blockhash();
+
+        env
+    }
+
+    fn new_child_bank(self, slot: Slot) -> LocalEnvironment {
+        let parent = Arc::new(self.bank);
+        let bank = Bank::new_from_parent(&parent, parent.collector_id(), slot);
+        LocalEnvironment { bank, ..self }
+    }
+
+    /// Moves the environment to the first slot of the given future epoch, see [LocalEnvironment::warp_to_slot].
+    pub fn warp_to_epoch(self, epoch: Epoch) -> LocalEnvironment {
+        let slot = self.bank.epoch_schedule().get_first_slot_in_epoch(epoch);
+        self.warp_to_slot(slot)
+    }
+
+    /// Moves the environment the given number of slots into the future, one slot at a time.
+    /// Every step creates a new bank for the next slot and calls [LocalEnvironment::advance_blockhash], so `advance_slots(1)` registers
+    /// the same single new blockhash as `advance_blockhash`, but in the next slot. Unlike [LocalEnvironment::warp_to_slot], no rent is collected. Advancing by zero slots does nothing.
+    pub fn advance_slots(self, n: u64) -> LocalEnvironment {
+        let mut env = self;
+        for _ in 0..n {
+            let slot = env.bank.slot() + 1;
+            env = env.new_child_bank(slot);
+            env.advance_blockhash();
+        }
+        env
+    }
+
+    /// Collects rent from all accounts for the current epoch. Rent-exempt accounts only get their `rent_epoch` updated.
+    fn collect_rent(&self) {
+        let rent_collector = self.bank.rent_collector();
+        for (pubkey, account) in self.accounts() {
+            let mut account = AccountSharedData::from(account);
+            rent_collector.collect_from_existing_account(&pubkey, &mut account, None, true);
+            self.bank
+                .store_account_and_update_capitalization(&pubkey, &account);
+        }
+    }
+
+    /// Registers the given number of ticks with the bank, without moving to a new slot.
+    /// Every `ticks_per_slot` ticks (see [GenesisConfig::ticks_per_slot]) complete a block and register a new blockhash,
+    /// which is what [LocalEnvironment::advance_blockhash] does in one go.
+    pub fn register_ticks(&self, n: u64) {
+        for _ in 0..n {
+            self.bank.register_tick(&Hash::new_unique());
+        }
+    }
+
+    /// Overwrites the clock sysvar of the current bank, e.g. to move the epoch or unix timestamp independently of the slot.
+    /// The runtime itself keeps using the slot of the bank, so a clock whose slot differs from [Bank::slot] creates an inconsistent state and is warned about.
+    /// The bank overwrites the clock again when moving to a new slot, e.g. with [LocalEnvironment::warp_to_slot].
+    pub fn set_clock(&mut self, clock: Clock) {
+        if clock.slot != self.bank.slot() {
+            log::warn!(
+                "setting clock with slot {}, but the bank is at slot {}",
+                clock.slot,
+                self.bank.slot()
+            );
+        }
+        self.bank.set_sysvar_for_tests(&clock);
+    }
+
+    /// Returns all accounts owned by the program whose data contains the given bytes at the given offset, sorted by pubkey.
+    pub fn find_accounts(
+        &self,
+        program_id: Pubkey,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Vec<(Pubkey, Account)> {
+        let mut accounts = self
+            .bank
+            .get_program_accounts(&program_id, &ScanConfig::default())
+            .expect("failed to scan accounts")
+            .into_iter()
+            .filter(|(_, account)| {
+                account
+                    .data()
+                    .get(offset..offset.saturating_add(bytes.len()))
+                    .map_or(false, |data| data == bytes)
+            })
+            .map(|(pubkey, account)| (pubkey, account.into()))
+            .collect::<Vec<(Pubkey, Account)>>();
+        accounts.sort_by_key(|(pubkey, _)| *pubkey);
+        accounts
+    }
+
+    /// Returns the up to 20 largest token accounts of the given mint with their balances, largest first.
+    /// Mirrors the `getTokenLargestAccounts` rpc request by scanning all accounts of the token program of the mint.
+    pub fn get_token_largest_accounts(&self, mint: Pubkey) -> Vec<(Pubkey, u64)> {
+        let token_program = token_program_of_mint(self.get_account(mint));
+        let mut accounts = self
+            .bank
+            .get_program_accounts(&token_program, &ScanConfig::default())
+            .expect("failed to scan accounts")
+            .into_iter()
+            .filter_map(|(pubkey, _)| {
+                let info = self.get_token_account_info(pubkey)?;
+                Some((pubkey, info)).filter(|(_, info)| info.mint == mint)
+            })
+            .map(|(pubkey, info)| (pubkey, info.amount))
+            .collect::<Vec<_>>();
+        accounts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        accounts.truncate(20);
+        accounts
+    }
+
+    /// Returns all non-empty accounts in the environment, sorted by pubkey.
+    pub fn accounts(&self) -> Vec<(Pubkey, Account)> {
+        let mut accounts = self
+            .bank
+            .get_all_accounts_with_modified_slots()
+            .expect("failed to scan accounts")
+            .into_iter()
+            .filter(|(_, account, _)| account.lamports() > 0)
+            .map(|(pubkey, account, _)| (pubkey, account.into()))
+            .collect::<Vec<(Pubkey, Account)>>();
+        accounts.sort_by_key(|(pubkey, _)| *pubkey);
+        accounts
+    }
+
+    /// Prints pubkey, owner, lamports and data length of all accounts in the environment.
+    pub fn dump_accounts(&self) {
+        for (pubkey, account) in self.accounts() {
+            println!(
+                "{} owner: {} lamports: {} data: {} bytes{}",
+                pubkey,
+                account.owner,
+                account.lamports,
+                account.data.len(),
+                if account.executable {
+                    " (executable)"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+
+    /// Creates an environment on a fresh bank for the genesis config. Logs, timings and signature statuses start out empty.
+    fn from_genesis(
+        genesis_config: GenesisConfig,
+        faucet: Keypair,
+        builtins: Vec<(String, Pubkey, ProcessInstructionWithContext)>,
+        refuel_lamports: Option<u64>,
+        settings: Settings,
+    ) -> LocalEnvironment {
+        LocalEnvironment {
+            bank: new_bank(&genesis_config, settings.compute_budget, &builtins),
+            faucet,
+            genesis_config,
+            builtins,
+            settings,
+            last_logs: vec![],
+            signature_statuses: HashMap::new(),
+            refuel_lamports,
+            last_timings: None,
+            recorded_transactions: vec![],
+        }
+    }
+
+    /// Returns the genesis config of the environment with all current accounts, except for sysvars, which the bank recreates.
+    fn genesis_with_accounts(&self) -> GenesisConfig {
+        let mut genesis_config = self.genesis_config.clone();
+        genesis_config.accounts = self
+            .accounts()
+            .into_iter()
+            .filter(|(_, account)| account.owner != sysvar::ID)
+            .collect();
+        genesis_config
+    }
+
+    /// Sets the compute budget used for every following transaction, like [LocalEnvironmentBuilder::set_compute_budget].
+    /// The budget is part of the bank configuration, so the bank is rebuilt from the current accounts at the current slot and latest blockhash.
+    /// Older blockhashes are forgotten and sysvars are recreated by the new bank; no rent is collected.
+    pub fn set_compute_budget(&mut self, budget: ComputeBudget) {
+        let settings = Settings {
+            compute_budget: Some(budget),
+            ..self.settings
+        };
+        let mut env = LocalEnvironment::from_genesis(
+            self.genesis_with_accounts(),
+            clone_keypair(&self.faucet),
+            self.builtins.clone(),
+            self.refuel_lamports,
+            Settings {
+                collect_rent_on_warp: false,
+                ..settings
+            },
+        );
+        if self.bank.slot() > env.bank.slot() {
+            env = env.warp_to_slot(self.bank.slot());
+        }
+        env.register_blockhash_as(&self.bank.last_blockhash());
+        self.bank = env.bank;
+        self.settings = settings;
+    }
+
+    /// Creates an independent copy of the environment, containing all accounts of this environment except sysvars.
+    /// The copy is built from a fresh bank, so slot, blockhashes and the clock sysvar are reset, like after [LocalEnvironmentBuilder::build].
+    pub fn fork(&self) -> LocalEnvironment {
+        let env = LocalEnvironment::from_genesis(
+            self.genesis_with_accounts(),
+            clone_keypair(&self.faucet),
+            self.builtins.clone(),
+            self.refuel_lamports,
+            self.settings,
+        );
+        env.advance_blockhash();
+
+        env
+    }
+
+    /// Writes all accounts of the environment, together with its genesis config, faucet keypair, slot, latest blockhash and settings, into the given file.
+    /// The environment can be restored with [LocalEnvironmentBuilder::load_state], without access to the cluster it was cloned from.
+    pub fn save_state<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let state = (
+            self.genesis_with_accounts(),
+            self.faucet.to_bytes().to_vec(),
+            self.bank.slot(),
+            self.bank.last_blockhash(),
+            self.refuel_lamports,
+            self.settings.to_saved(),
+        );
+        bincode::serialize_into(File::create(path)?, &state)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    }
+
+    /// Captures the current state of all accounts, e.g. for [LocalEnvironment::assert_only_changed].
+    pub fn snapshot(&self) -> EnvSnapshot {
+        EnvSnapshot {
+            accounts: self.accounts().into_iter().collect(),
+        }
+    }
+
+    /// Restores all accounts to the state captured in the snapshot. Accounts created after the snapshot are removed.
+    /// Only accounts are restored, the slot, blockhashes and the status cache of executed transactions are kept.
+    pub fn restore(&mut self, snapshot: &EnvSnapshot) {
+        let current = self.snapshot();
+        for pubkey in current.accounts.keys() {
+            if !snapshot.accounts.contains_key(pubkey) {
+                self.bank
+                    .store_account_and_update_capitalization(pubkey, &AccountSharedData::default());
+            }
+        }
+        for (pubkey, account) in &snapshot.accounts {
+            if current.get_account(*pubkey) != Some(account) {
+                self.bank.store_account_and_update_capitalization(
+                    pubkey,
+                    &AccountSharedData::from(account.clone()),
+                );
+            }
+        }
+    }
+
+    /// Executes the transactions, then restores all accounts to their previous state and returns the execution results.
+    /// Unlike a simulation, the transactions go through the full commit path, so inner instructions and token balances are recorded.
+    pub fn try_execute_and_rollback<T>(
+        &mut self,
+        txs: Vec<T>,
+    ) -> Vec<EncodedConfirmedTransactionWithStatusMeta>
+    where
+        VersionedTransaction: From<T>,
+    {
+        let before = self.snapshot();
+        let results = self.execute_transactions(txs);
+        self.restore(&before);
+        results
+    }
+
+    /// Panics if any account outside of the allowed set changed since the snapshot was taken, listing the unexpectedly changed accounts.
+    /// The faucet, which pays the fees, and sysvars are always allowed to change.
+    pub fn assert_only_changed(&self, before: &EnvSnapshot, allowed: &[Pubkey]) {
+        let after = self.snapshot();
+        let mut unexpected = before
+            .accounts
+            .keys()
+            .chain(after.accounts.keys())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .filter(|&&pubkey| {
+                before.get_account(pubkey) != after.get_account(pubkey)
+                    && !allowed.contains(&pubkey)
+                    && pubkey != self.faucet.pubkey()
+                    && !sysvar::is_sysvar_id(&pubkey)
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        unexpected.sort();
+        if !unexpected.is_empty() {
+            panic!("unexpected changes to accounts {:?}", unexpected);
+        }
+    }
+
+    /// Returns the program logs of the most recently executed transaction.
+    pub fn last_logs(&self) -> &[String] {
+        &self.last_logs
+    }
+
+    /// Returns the load, execute and store timings of the most recently executed transaction.
+    /// None unless enabled with [LocalEnvironmentBuilder::set_collect_timings].
+    pub fn last_timings(&self) -> Option<&ExecuteTimings> {
+        self.last_timings.as_ref()
+    }
+
+    /// Snapshots the watched accounts, runs the closure and returns its result together with the changes made to the watched accounts.
+    /// Accounts that do not exist are treated as empty accounts with zero lamports.
+    pub fn with_account_diff<F, R>(&mut self, watched: &[Pubkey], f: F) -> (R, Vec<AccountDiff>)
+    where
+        F: FnOnce(&mut Self) -> R,
+    {
+        let before = watched
+            .iter()
+            .map(|&pubkey| self.get_account(pubkey))
+            .collect::<Vec<_>>();
+        let result = f(self);
+        let diffs = watched
+            .iter()
+            .zip(before)
+            .map(|(&pubkey, before)| {
+                AccountDiff::new(pubkey, before.as_ref(), self.get_account(pubkey).as_ref())
+            })
+            .collect();
+        (result, diffs)
+    }
+
+    /// Loads the account, lets the closure modify lamports, data, owner etc. and stores the result back into the bank.
+    /// Useful to patch a few bytes of an account to reproduce corrupted state. Panics if the account does not exist.
+    pub fn mutate_account<F: FnOnce(&mut Account)>(&mut self, pubkey: Pubkey, f: F) {
+        let mut account = self
+            .get_account(pubkey)
+            .unwrap_or_else(|| panic!("cannot mutate account {}: account does not exist", pubkey));
+        f(&mut account);
+        self.bank
+            .store_account_and_update_capitalization(&pubkey, &AccountSharedData::from(account));
+    }
+
+    /// Assemble the given instructions into a transaction payed for by the given pubkey and execute it, without a signature of the payer.
+    /// Only usable if signature verification is disabled with [LocalEnvironmentBuilder::set_verify_signatures].
+    pub fn execute_as_payer_pubkey(
+        &mut self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+        payer: Pubkey,
+    ) -> EncodedConfirmedTransactionWithStatusMeta {
+        assert!(
+            !self.settings.verify_signatures,
+            "executing without a payer signature requires signature verification to be disabled"
+        );
+        let message = Message::new(instructions, Some(&payer));
+        let report = SignerReport::new(&message, signers);
+        let signers = signers
+            .iter()
+            .copied()
+            .filter(|signer| !report.unnecessary.contains(&signer.pubkey()))
+            .collect::<Vec<_>>();
+        let mut tx = Transaction::new_unsigned(message);
+        tx.partial_sign(&signers, self.get_latest_blockhash());
+        self.execute_transaction(tx)
+    }
+
+    /// Stores the given address lookup tables into the bank and executes the v0 transaction referencing them.
+    /// Existing accounts at the table addresses are overwritten, so the transaction sees exactly the given addresses.
+    /// In dry-run mode the tables are not stored and the transaction is only recorded.
+    pub fn execute_versioned_with_luts(
+        &mut self,
+        tx: VersionedTransaction,
+        tables: &[AddressLookupTableAccount],
+    ) -> EncodedConfirmedTransactionWithStatusMeta {
+        if self.settings.dry_run {
+            return self.execute_transaction(tx);
+        }
+        for table in tables {
+            // addresses extended in a past slot are all active. In the first slot there is none,
+            // so the start index of the current slot is moved past all addresses instead.
+            let meta = match self.bank.slot().checked_sub(1) {
+                Some(past_slot) => LookupTableMeta {
+                    last_extended_slot: past_slot,
+                    last_extended_slot_start_index: 0,
+                    ..LookupTableMeta::default()
+                },
+                None => LookupTableMeta {
+                    last_extended_slot: 0,
+                    last_extended_slot_start_index: u8::try_from(table.addresses.len()).expect(
+                        "lookup tables with 256 addresses require a slot > 0, see LocalEnvironment::advance_slots",
+                    ),
+                    ..LookupTableMeta::default()
+                },
+            };
+            let data = AddressLookupTable {
+                meta,
+                addresses: Cow::Borrowed(&table.addresses),
+            }
+            .serialize_for_tests()
+            .expect("failed to serialize lookup table");
+            let mut account = AccountSharedData::new(
+                self.get_rent_excemption(data.len()),
+                data.len(),
+                &solana_address_lookup_table_program::ID,
+            );
+            account.set_data_from_slice(&data);
+            self.bank.store_account(&table.key, &account);
+        }
+        self.execute_transaction(tx)
+    }
+
+    /// Creates a copy of the source account at the address of the given keypair, with the same lamports, data and owner.
+    /// The account is written into the bank directly, since its data can not be set through transactions for arbitrary owners.
+    pub fn clone_account_to_keypair(&mut self, source: Pubkey, dest: &Keypair) {
+        let account = self
+            .get_account(source)
+            .unwrap_or_else(|| panic!("cannot clone account {}: account does not exist", source));
+        self.bank.store_account_and_update_capitalization(
+            &dest.pubkey(),
+            &AccountSharedData::from(account),
+        );
+    }
+
+    /// Executes the transaction and returns the execution results, or an error if the transaction could not be handed to the bank.
+    /// Unlike [Environment::execute_transaction], this does not panic on oversized transactions.
+    pub fn try_execute_transaction<T>(
+        &mut self,
+        tx: T,
+    ) -> Result<EncodedConfirmedTransactionWithStatusMeta, ExecError>
+    where
+        VersionedTransaction: From<T>,
+    {
+        let tx = VersionedTransaction::from(tx);
+        self.check_transaction(&tx)?;
+        if self.settings.dry_run {
+            return Ok(self.record_transaction(tx));
+        }
+        self.sanitize_and_execute(tx)
+    }
+
+    /// Executes the transactions one after another. None of the transactions is executed if any of them fails the size or signature checks,
+    /// in which case the errors of all failing transactions are returned.
+    /// Otherwise every transaction is handed to the bank, and one result per transaction is returned in the same order,
+    /// so the results of transactions executed before a failing one are kept.
+    pub fn try_execute_transactions<T>(
+        &mut self,
+        txs: Vec<T>,
+    ) -> Result<Vec<Result<EncodedConfirmedTransactionWithStatusMeta, ExecError>>, Vec<ExecError>>
+    where
+        VersionedTransaction: From<T>,
+    {
+        let txs: Vec<VersionedTransaction> =
+            txs.into_iter().map(VersionedTransaction::from).collect();
+        let errors: Vec<ExecError> = txs
+            .iter()
+            .filter_map(|tx| self.check_transaction(tx).err())
+            .collect();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        Ok(txs
+            .into_iter()
+            .map(|tx| {
+                if self.settings.dry_run {
+                    Ok(self.record_transaction(tx))
+                } else {
+                    self.sanitize_and_execute(tx)
+                }
+            })
+            .collect())
+    }
+
+    fn check_transaction(&self, tx: &VersionedTransaction) -> Result<(), ExecError> {
+        let size = bincode::serialize(tx).unwrap().len();
+        if size > self.settings.max_tx_size {
+            return Err(ExecError::TransactionTooLarge {
+                size,
+                overflow: size - self.settings.max_tx_size,
+            });
+        }
+        if self.settings.verify_signatures
+            && !tx.verify_with_results().into_iter().all(|valid| valid)
+        {
+            return Err(ExecError::SignatureFailure);
+        }
+        Ok(())
+    }
+
+    fn sanitize_and_execute(
+        &mut self,
+        tx: VersionedTransaction,
+    ) -> Result<EncodedConfirmedTransactionWithStatusMeta, ExecError> {
+        let batch = self
+            .bank
+            .prepare_entry_batch(vec![tx])
+            .map_err(ExecError::Rejected)?;
+        let tx_sanitized = batch.sanitized_transactions()[0].clone();
+        drop(batch);
+        self.try_execute_sanitized(tx_sanitized)
+    }
+
+    /// Panics with a descriptive message if a fee payer of the batch runs out of lamports for the fees of its transactions.
+    /// An auto-refueled faucet is skipped, since it is topped up before every transaction.
+    fn assert_payers_can_pay(&self, txs: &[VersionedTransaction]) {
+        let refueled = self.refuel_lamports.map(|_| self.faucet.pubkey());
+        let mut balances: HashMap<Pubkey, u128> = HashMap::new();
+        for tx in txs {
+            // messages using lookup tables can not be checked without loading the tables
+            let message = match &tx.message {
+                VersionedMessage::Legacy(message) => message,
+                VersionedMessage::V0(_) => continue,
+            };
+            // transactions with an unknown blockhash fail without charging a fee
+            let fee = match SanitizedMessage::try_from(message.clone())
+                .ok()
+                .and_then(|message| self.bank.get_fee_for_message(&message))
+            {
+                Some(fee) => fee as u128,
+                None => continue,
+            };
+            let payer = message.account_keys[0];
+            let balance = balances
+                .entry(payer)
+                .or_insert_with(|| self.bank.get_balance(&payer) as u128);
+            if Some(payer) != refueled && *balance < fee {
+                panic!(
+                    "payer {} has {} lamports left in the batch, needs {}",
+                    payer, balance, fee
+                );
+            }
+            *balance = balance.saturating_sub(fee);
+
+            for (from, to, lamports) in system_transfers(message) {
+                let from_balance = balances
+                    .entry(from)
+                    .or_insert_with(|| self.bank.get_balance(&from) as u128);
+                *from_balance = from_balance.saturating_sub(lamports as u128);
+                *balances
+                    .entry(to)
+                    .or_insert_with(|| self.bank.get_balance(&to) as u128) += lamports as u128;
+            }
+        }
+    }
+
+    /// Returns the transactions recorded in dry-run mode, in the order they were executed.
+    pub fn recorded_transactions(&self) -> Vec<VersionedTransaction> {
+        self.recorded_transactions.clone()
+    }
+
+    fn record_transaction(
+        &mut self,
+        tx: VersionedTransaction,
+    ) -> EncodedConfirmedTransactionWithStatusMeta {
+        self.recorded_transactions.push(tx.clone());
+        self.signature_statuses.insert(tx.signatures[0], Ok(()));
+        ConfirmedTransactionWithStatusMeta {
+            slot: self.bank.slot(),
+            tx_with_meta: TransactionWithStatusMeta::Complete(VersionedTransactionWithStatusMeta {
+                transaction: tx,
+                meta: TransactionStatusMeta::default(),
+            }),
+            block_time: None,
+        }
+        .encode(UiTransactionEncoding::Binary, Some(0))
+        .expect("Failed to encode transaction")
+    }
+
+    /// Executes an already sanitized transaction and returns the execution results.
+    /// The transaction is handed to the bank directly, skipping the size and signature checks of [LocalEnvironment::try_execute_transaction].
+    /// Like on a cluster, the bank populates the instructions sysvar (including the current instruction index) for transactions referencing it,
+    /// so programs relying on instruction introspection, e.g. for ed25519 or secp256k1 signature checks, need no manual setup.
+    /// In dry-run mode the transaction is only recorded.
+    pub fn execute_sanitized(
+        &mut self,
+        tx: SanitizedTransaction,
+    ) -> EncodedConfirmedTransactionWithStatusMeta {
+        if self.settings.dry_run {
+            return self.record_transaction(tx.to_versioned_transaction());
+        }
+        self.try_execute_sanitized(tx)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    fn try_execute_sanitized(
+        &mut self,
+        tx: SanitizedTransaction,
+    ) -> Result<EncodedConfirmedTransactionWithStatusMeta, ExecError> {
+        if let Some(refuel_lamports) = self.refuel_lamports {
+            let faucet = self.faucet.pubkey();
+            let mut account = self
+                .bank
+                .get_account(&faucet)
+                .unwrap_or_else(|| AccountSharedData::new(0, 0, &system_program::ID));
+            if account.lamports() < refuel_lamports / 2 {
+                account
+                    .checked_add_lamports(refuel_lamports - account.lamports())
+                    .unwrap();
+                self.bank
+                    .store_account_and_update_capitalization(&faucet, &account);
+            }
+        }
+
+        // an unknown or expired blockhash has no fee; the bank then fails the transaction with BlockhashNotFound below
+        let fee = self.bank.get_fee_for_message(tx.message()).unwrap_or(0);
+
+        let txs = [tx];
+        let batch = self.bank.prepare_sanitized_batch(&txs);
 
         let mut mint_decimals = HashMap::new();
         let tx_pre_token_balances = solana_ledger::token_balances::collect_token_balances(
@@ -466,7 +1984,12 @@ impl Environment for LocalEnvironment {
             &mut mint_decimals,
         );
         let slot = self.bank.slot();
-        let mut timings = Default::default();
+        let max_age = if self.settings.enforce_blockhash_expiry {
+            MAX_PROCESSING_AGE
+        } else {
+            usize::MAX
+        };
+        let mut timings = ExecuteTimings::default();
         let (
             TransactionResults {
                 execution_results, ..
@@ -478,13 +2001,13 @@ impl Environment for LocalEnvironment {
             },
         ) = self.bank.load_execute_and_commit_transactions(
             &batch,
-            usize::MAX,
+            max_age,
             true,
             true,
             true,
             true,
             &mut timings,
-            None,
+            self.settings.log_messages_bytes_limit,
         );
 
         let tx_post_token_balances = solana_ledger::token_balances::collect_token_balances(
@@ -506,12 +2029,9 @@ impl Environment for LocalEnvironment {
             post_balances.into_iter(),
             tx_pre_token_balances.into_iter(),
             tx_post_token_balances.into_iter(),
-        ).next().expect("transaction could not be executed. Enable debug logging to get more information on why");
-
-        let fee = self
-            .bank
-            .get_fee_for_message(tx_sanitized.message())
-            .expect("Fee calculation must succeed");
+        )
+        .next()
+        .ok_or(ExecError::NotExecuted)?;
 
         let status;
         let inner_instructions;
@@ -536,6 +2056,13 @@ impl Environment for LocalEnvironment {
             }
         }
 
+        self.last_logs = log_messages.clone().unwrap_or_default();
+        if self.settings.collect_timings {
+            self.last_timings = Some(timings);
+        }
+        self.signature_statuses
+            .insert(*tx.signature(), status.clone());
+
         let inner_instructions = inner_instructions.map(|inner_instructions| {
             inner_instructions
                 .into_iter()
@@ -567,15 +2094,15 @@ impl Environment for LocalEnvironment {
             inner_instructions,
             log_messages,
             rewards: None,
-            loaded_addresses: tx_sanitized.get_loaded_addresses(),
+            loaded_addresses: tx.get_loaded_addresses(),
             return_data,
             compute_units_consumed,
         };
 
-        ConfirmedTransactionWithStatusMeta {
+        let encoded = ConfirmedTransactionWithStatusMeta {
             slot,
             tx_with_meta: TransactionWithStatusMeta::Complete(VersionedTransactionWithStatusMeta {
-                transaction: tx.clone(),
+                transaction: tx.to_versioned_transaction(),
                 meta: tx_status_meta,
             }),
             block_time: Some(
@@ -587,8 +2114,78 @@ impl Environment for LocalEnvironment {
                     .unwrap(),
             ),
         }
-        .encode(UiTransactionEncoding::Binary, Some(0))
-        .expect("Failed to encode transaction")
+        .encode(UiTransactionEncoding::Binary, Some(0))
+        .expect("Failed to encode transaction");
+        Ok(encoded)
+    }
+}
+
+impl Environment for LocalEnvironment {
+    fn payer(&self) -> Keypair {
+        clone_keypair(&self.faucet)
+    }
+
+    fn payer_preflight(&self) -> bool {
+        // an auto-refueled faucet is topped up right before execution
+        self.settings.payer_preflight && self.refuel_lamports.is_none()
+    }
+
+    fn execute_transaction<T>(&mut self, tx: T) -> EncodedConfirmedTransactionWithStatusMeta
+    where
+        VersionedTransaction: From<T>,
+    {
+        let tx = VersionedTransaction::from(tx);
+        match self.try_execute_transaction(tx.clone()) {
+            Ok(result) => result,
+            Err(err) => panic!("{}: {:?}", err, tx),
+        }
+    }
+
+    /// Every transaction pays its fee from its own fee payer. Before anything is executed, each fee payer is checked to cover the fees
+    /// of all its transactions in the batch, taking system transfers of earlier transactions into account.
+    fn execute_transactions<T>(
+        &mut self,
+        txs: Vec<T>,
+    ) -> Vec<EncodedConfirmedTransactionWithStatusMeta>
+    where
+        VersionedTransaction: From<T>,
+    {
+        let txs: Vec<VersionedTransaction> =
+            txs.into_iter().map(VersionedTransaction::from).collect();
+        if self.settings.payer_preflight {
+            self.assert_payers_can_pay(&txs);
+        }
+        match self.try_execute_transactions(txs) {
+            Ok(results) => results
+                .into_iter()
+                .enumerate()
+                .map(|(index, result)| {
+                    result.unwrap_or_else(|err| panic!("transaction {}: {}", index, err))
+                })
+                .collect(),
+            Err(errors) => panic!("transactions could not be executed: {:?}", errors),
+        }
+    }
+
+    /// Executes the transaction immediately.
+    fn send_transaction<T>(&mut self, tx: T) -> Signature
+    where
+        VersionedTransaction: From<T>,
+    {
+        let tx = VersionedTransaction::from(tx);
+        let signature = tx.signatures[0];
+        self.execute_transaction(tx);
+        signature
+    }
+
+    /// Returns the status of an already executed transaction. Panics if the transaction was never executed.
+    fn confirm(&self, signature: Signature) -> Result<(), TransactionError> {
+        self.get_signature_status(signature)
+            .unwrap_or_else(|| panic!("transaction {} was not executed", signature))
+    }
+
+    fn get_signature_status(&self, signature: Signature) -> Option<Result<(), TransactionError>> {
+        self.signature_statuses.get(&signature).cloned()
     }
 
     fn get_latest_blockhash(&self) -> Hash {
@@ -599,14 +2196,208 @@ impl Environment for LocalEnvironment {
         self.bank.get_minimum_balance_for_rent_exemption(data)
     }
 
+    fn get_rent(&self) -> Rent {
+        self.bank.rent_collector().rent.clone()
+    }
+
     fn get_account(&self, pubkey: Pubkey) -> Option<Account> {
         self.bank.get_account(&pubkey).map(|acc| acc.into())
     }
+
+    fn get_account_with_commitment(
+        &self,
+        pubkey: Pubkey,
+        _commitment: CommitmentConfig,
+    ) -> Option<Account> {
+        self.get_account(pubkey)
+    }
+
+    fn get_epoch_info(&self) -> EpochInfo {
+        let absolute_slot = self.bank.slot();
+        let (epoch, slot_index) = self.bank.get_epoch_and_slot_index(absolute_slot);
+        EpochInfo {
+            epoch,
+            slot_index,
+            slots_in_epoch: self.bank.get_slots_in_epoch(epoch),
+            absolute_slot,
+            block_height: self.bank.block_height(),
+            transaction_count: Some(self.bank.transaction_count()),
+        }
+    }
+
+    fn get_block_height(&self) -> u64 {
+        self.bank.block_height()
+    }
+
+    fn estimate_fee(&self, message: &Message) -> u64 {
+        let message = SanitizedMessage::try_from(message.clone()).expect("invalid message");
+        self.bank
+            .get_fee_for_message(&message)
+            .expect("Fee calculation must succeed")
+    }
+
+    fn get_minimum_stake_delegation(&self) -> u64 {
+        solana_stake_program::get_minimum_delegation(&self.bank.feature_set)
+    }
+
+    fn airdrop(&mut self, account: Pubkey, lamports: u64) {
+        let mut acc = self
+            .bank
+            .get_account(&account)
+            .unwrap_or_else(|| AccountSharedData::new(0, 0, &system_program::ID));
+        acc.checked_add_lamports(lamports).unwrap();
+        self.bank
+            .store_account_and_update_capitalization(&account, &acc);
+    }
+}
+
+/// State of all accounts of a [LocalEnvironment] at a point in time.
+#[derive(Debug, Clone)]
+pub struct EnvSnapshot {
+    accounts: HashMap<Pubkey, Account>,
+}
+
+impl EnvSnapshot {
+    /// The account at the time of the snapshot. None if it did not exist.
+    pub fn get_account(&self, pubkey: Pubkey) -> Option<&Account> {
+        self.accounts.get(&pubkey)
+    }
+}
+
+/// The changes made to a single account, see [LocalEnvironment::with_account_diff].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountDiff {
+    pub pubkey: Pubkey,
+    /// Change in lamports. Positive if the account gained lamports.
+    pub lamport_delta: i128,
+    pub data_len_before: usize,
+    pub data_len_after: usize,
+    /// Byte ranges of the account data that differ. Bytes only present in one of the versions count as changed.
+    pub data_changes: Vec<Range<usize>>,
+}
+
+impl AccountDiff {
+    /// Computes the difference between two versions of an account.
+    pub fn new(pubkey: Pubkey, before: Option<&Account>, after: Option<&Account>) -> Self {
+        let lamports = |acc: Option<&Account>| acc.map_or(0, |acc| acc.lamports) as i128;
+        let data = |acc: Option<&Account>| acc.map_or(&[][..], |acc| &acc.data[..]);
+        let (data_before, data_after) = (data(before), data(after));
+
+        let mut data_changes: Vec<Range<usize>> = vec![];
+        for i in 0..data_before.len().max(data_after.len()) {
+            if data_before.get(i) == data_after.get(i) {
+                continue;
+            }
+            match data_changes.last_mut() {
+                Some(range) if range.end == i => range.end += 1,
+                _ => data_changes.push(i..i + 1),
+            }
+        }
+
+        AccountDiff {
+            pubkey,
+            lamport_delta: lamports(after) - lamports(before),
+            data_len_before: data_before.len(),
+            data_len_after: data_after.len(),
+            data_changes,
+        }
+    }
+
+    /// Returns true if neither lamports nor data of the account changed.
+    pub fn is_unchanged(&self) -> bool {
+        self.lamport_delta == 0 && self.data_changes.is_empty()
+    }
+}
+
+/// Error returned when the data of an account could not be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub pubkey: Pubkey,
+    pub message: String,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to decode account {}: {}",
+            self.pubkey, self.message
+        )
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Errors that prevent a transaction from being executed by a [LocalEnvironment].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecError {
+    /// The serialized transaction does not fit into a packet.
+    TransactionTooLarge { size: usize, overflow: usize },
+    /// At least one of the signatures of the transaction is invalid.
+    SignatureFailure,
+    /// The bank refused the transaction before execution, e.g. because it could not be sanitized.
+    Rejected(TransactionError),
+    /// The bank returned no execution result for the transaction.
+    NotExecuted,
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecError::TransactionTooLarge { size, overflow } => {
+                write!(f, "transaction of size {} is {} too large", size, overflow)
+            }
+            ExecError::SignatureFailure => write!(f, "transaction has invalid signatures"),
+            ExecError::Rejected(err) => write!(f, "transaction was rejected: {}", err),
+            ExecError::NotExecuted => write!(
+                f,
+                "transaction could not be executed. Enable debug logging to get more information on why"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ExecError {}
+
+/// Program id of the Metaplex token-metadata program.
+pub const TOKEN_METADATA_PROGRAM_ID: Pubkey =
+    solana_program::pubkey!("metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s");
+
+// Layout constants of the token-metadata program, see `mpl_token_metadata::state`
+const METADATA_KEY_V1: u8 = 4;
+const MAX_NAME_LENGTH: usize = 32;
+const MAX_SYMBOL_LENGTH: usize = 10;
+const MAX_URI_LENGTH: usize = 200;
+const MAX_METADATA_LEN: usize = 679;
+
+/// The starting balance of the faucet of a [LocalEnvironment].
+pub const DEFAULT_FAUCET_LAMPORTS: u64 = 1u64 << 48;
+
+/// Progress of a clone operation of [LocalEnvironmentBuilder], reported once per loaded account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CloneProgress {
+    /// Number of accounts loaded so far, including this one.
+    pub current: usize,
+    /// Number of accounts loaded by the operation.
+    pub total: usize,
+    pub pubkey: Pubkey,
+    /// Data length of the loaded account.
+    pub bytes: usize,
 }
 
+const SPL_ACCOUNT_COMPRESSION_ID: Pubkey =
+    solana_program::pubkey!("cmtDvXumGCrqC1Age74AVPhSRVXJMd8PJS91L8KbNCK");
+const SPL_NOOP_ID: Pubkey = solana_program::pubkey!("noopb9bkMVfRPU8AsbpTUg8AQkHtKwMYZiFUjNRtMmV");
+const SPL_MEMO1_ID: Pubkey = solana_program::pubkey!("Memo1UhkJRfHyvLMcVucJwxXeuD728EqVDDwQDxFMNo");
+
 pub struct LocalEnvironmentBuilder {
     config: GenesisConfig,
     faucet: Keypair,
+    builtins: Vec<(String, Pubkey, ProcessInstructionWithContext)>,
+    settings: Settings,
+    auto_refuel: bool,
+    sysvars: Vec<(Pubkey, AccountSharedData)>,
+    progress_callback: Option<Box<dyn Fn(CloneProgress) + Send + Sync>>,
 }
 
 impl LocalEnvironmentBuilder {
@@ -615,7 +2406,7 @@ impl LocalEnvironmentBuilder {
         let mut config = GenesisConfig::new(
             &[(
                 faucet.pubkey(),
-                AccountSharedData::new(1u64 << 48, 0, &system_program::id()),
+                AccountSharedData::new(DEFAULT_FAUCET_LAMPORTS, 0, &system_program::id()),
             )],
             &[],
         );
@@ -625,33 +2416,203 @@ impl LocalEnvironmentBuilder {
             .accounts
             .remove(&feature_set::fix_recent_blockhashes::id());
 
-        let mut builder = LocalEnvironmentBuilder { faucet, config };
+        let mut builder = LocalEnvironmentBuilder {
+            faucet,
+            config,
+            builtins: vec![],
+            settings: Settings::default(),
+            auto_refuel: false,
+            sysvars: vec![],
+            progress_callback: None,
+        };
         builder.add_account_with_data(
             spl_associated_token_account::ID,
             bpf_loader::ID,
             programs::SPL_ASSOCIATED_TOKEN,
             true,
         );
-        builder.add_account_with_data(
-            "Memo1UhkJRfHyvLMcVucJwxXeuD728EqVDDwQDxFMNo"
-                .parse()
-                .unwrap(),
-            bpf_loader::ID,
-            programs::SPL_MEMO1,
-            true,
-        );
+        builder.add_account_with_data(SPL_MEMO1_ID, bpf_loader::ID, programs::SPL_MEMO1, true);
         builder.add_account_with_data(spl_memo::ID, bpf_loader::ID, programs::SPL_MEMO3, true);
         builder.add_account_with_data(spl_token::ID, bpf_loader::ID, programs::SPL_TOKEN, true);
         builder.add_account_with_lamports(rent::ID, sysvar::ID, 1);
+        builder.add_token_mint(spl_token::native_mint::ID, None, 0, 9, None);
         builder
     }
 
+    /// Sets the starting balance of the faucet, which pays for all transactions. Defaults to [DEFAULT_FAUCET_LAMPORTS].
+    pub fn set_faucet_balance(&mut self, lamports: u64) -> &mut Self {
+        let faucet = self.faucet.pubkey();
+        self.add_account_with_lamports(faucet, system_program::ID, lamports)
+    }
+
+    /// Sets the keypair of the faucet, which pays for all transactions. The faucet keeps its current balance.
+    /// Use this if the PoC requires a fixed, known payer. The collector id of the bank, which receives the fees, is not affected.
+    pub fn set_faucet_keypair(&mut self, keypair: Keypair) -> &mut Self {
+        let account = self
+            .config
+            .accounts
+            .remove(&self.faucet.pubkey())
+            .unwrap_or_else(|| Account::new(DEFAULT_FAUCET_LAMPORTS, 0, &system_program::ID));
+        self.faucet = keypair;
+        self.add_account(self.faucet.pubkey(), account)
+    }
+
     /// Sets the creation time of the network
     pub fn set_creation_time(&mut self, unix_timestamp: UnixTimestamp) -> &mut Self {
         self.config.creation_time = unix_timestamp as UnixTimestamp;
         self
     }
 
+    /// Sets the epoch schedule of the environment. Short epochs let [LocalEnvironment::warp_to_slot] cross epoch boundaries quickly.
+    pub fn set_epoch_schedule(&mut self, schedule: EpochSchedule) -> &mut Self {
+        self.config.epoch_schedule = schedule;
+        self
+    }
+
+    /// Sets the compute budget used for every transaction executed in the environment.
+    /// This overrides any compute budget instructions contained in the transactions themselves.
+    pub fn set_compute_budget(&mut self, budget: ComputeBudget) -> &mut Self {
+        self.settings.compute_budget = Some(budget);
+        self
+    }
+
+    /// Sets the maximum instruction stack height. Transaction-level instructions have a height of 1, each CPI increases it by one.
+    /// Like [LocalEnvironmentBuilder::set_compute_budget], this overrides compute budget instructions contained in the transactions.
+    pub fn set_max_invoke_depth(&mut self, depth: usize) -> &mut Self {
+        let mut budget = self.settings.compute_budget.unwrap_or_default();
+        budget.max_invoke_stack_height = depth;
+        self.set_compute_budget(budget)
+    }
+
+    /// Registers a native program implemented in Rust at the given address.
+    /// Useful to stub out CPI targets without having to compile a BPF program.
+    pub fn add_builtin(
+        &mut self,
+        name: &str,
+        program_id: Pubkey,
+        entrypoint: ProcessInstructionWithContext,
+    ) -> &mut Self {
+        self.builtins
+            .push((name.to_string(), program_id, entrypoint));
+        self
+    }
+
+    /// Rejects transactions whose blockhash is older than `MAX_PROCESSING_AGE` with `BlockhashNotFound`, like a real cluster would.
+    /// Disabled by default, so stale blockhashes are accepted.
+    pub fn enforce_blockhash_expiry(&mut self, enforce: bool) -> &mut Self {
+        self.settings.enforce_blockhash_expiry = enforce;
+        self
+    }
+
+    /// Sets the maximum amount of bytes of program logs recorded per transaction. `None` means unlimited.
+    /// If not set, the runtime default limit is used.
+    pub fn set_log_bytes_limit(&mut self, limit: Option<usize>) -> &mut Self {
+        self.settings.log_messages_bytes_limit = Some(limit.unwrap_or(usize::MAX));
+        self
+    }
+
+    /// Sets whether transaction signatures are verified before execution. Enabled by default.
+    /// Disabling this allows executing transactions with forged or missing signatures, which is not possible on a real cluster.
+    /// Only use this to demonstrate assumptions made by a program, never as proof of an exploit.
+    pub fn set_verify_signatures(&mut self, verify: bool) -> &mut Self {
+        self.settings.verify_signatures = verify;
+        self
+    }
+
+    /// Automatically tops up the faucet to its starting balance whenever it drops below half of it. Disabled by default.
+    /// Prevents long PoCs from failing due to accumulated fees, at the cost of exact balance accounting for the faucet.
+    pub fn set_auto_refuel(&mut self, enabled: bool) -> &mut Self {
+        self.auto_refuel = enabled;
+        self
+    }
+
+    /// Runs the rent collector over all accounts when warping the environment to a later slot. Enabled by default.
+    /// When enabled, `rent_epoch` advances like on a real cluster and rent-paying accounts are charged.
+    pub fn set_collect_rent_on_warp(&mut self, enabled: bool) -> &mut Self {
+        self.settings.collect_rent_on_warp = enabled;
+        self
+    }
+
+    /// Keeps the execution timings of the most recent transaction, see [LocalEnvironment::last_timings]. Disabled by default.
+    pub fn set_collect_timings(&mut self, enabled: bool) -> &mut Self {
+        self.settings.collect_timings = enabled;
+        self
+    }
+
+    /// Removes the programs seeded by default (spl-token, spl-associated-token-account, spl-memo v1 and v3) and the wrapped SOL mint.
+    /// Only the faucet and sysvars remain, so exactly the desired program versions can be added.
+    pub fn without_default_programs(&mut self) -> &mut Self {
+        for program in [
+            spl_associated_token_account::ID,
+            SPL_MEMO1_ID,
+            spl_memo::ID,
+            spl_token::ID,
+            spl_token::native_mint::ID,
+        ] {
+            self.without_program(program);
+        }
+        self
+    }
+
+    /// Removes a single account, e.g. one of the programs seeded by default, from the environment.
+    pub fn without_program(&mut self, pubkey: Pubkey) -> &mut Self {
+        self.config.accounts.remove(&pubkey);
+        self
+    }
+
+    /// Replaces the bytecode of a program already in the environment, e.g. one of the bundled SPL programs, with the given ELF.
+    /// Panics if no program exists at the given address.
+    pub fn override_program(&mut self, program_id: Pubkey, elf: &[u8]) -> &mut Self {
+        let owner = match self.config.accounts.get(&program_id) {
+            Some(account) if account.executable => account.owner,
+            _ => panic!("cannot override program {}: no such program", program_id),
+        };
+        self.add_account_with_data(program_id, owner, elf, true)
+    }
+
+    /// Overrides the given sysvar in the environment. The sysvar is written after the bank is created, so it takes precedence over the values derived from the genesis config.
+    /// Note that the bank keeps updating some sysvars, like the clock and slot hashes, when moving to a new slot.
+    /// Solana 1.16 has neither partitioned epoch rewards nor the `EpochRewards` sysvar, so there is no rewards distribution phase to set up.
+    pub fn set_sysvar<T: Sysvar>(&mut self, sysvar: T) -> &mut Self {
+        let mut account = AccountSharedData::new(
+            self.config.rent.minimum_balance(T::size_of()),
+            T::size_of(),
+            &sysvar::ID,
+        );
+        to_account(&sysvar, &mut account).expect("sysvar does not fit into its account");
+        self.sysvars.push((T::id(), account));
+        self
+    }
+
+    /// Reports the progress of clone operations to the given callback instead of printing a line per account.
+    pub fn set_progress_callback(
+        &mut self,
+        cb: Box<dyn Fn(CloneProgress) + Send + Sync>,
+    ) -> &mut Self {
+        self.progress_callback = Some(cb);
+        self
+    }
+
+    /// Checks that the payer can cover the fee and transferred lamports before executing transactions built by [Environment::execute_as_transaction]. Enabled by default.
+    /// Turns the opaque runtime error of an underfunded payer into a message stating the balance and the required lamports.
+    pub fn set_payer_preflight(&mut self, enabled: bool) -> &mut Self {
+        self.settings.payer_preflight = enabled;
+        self
+    }
+
+    /// Sets the maximum serialized size of transactions the environment accepts. Defaults to [packet::PACKET_DATA_SIZE].
+    pub fn set_max_tx_size(&mut self, bytes: usize) -> &mut Self {
+        self.settings.max_tx_size = bytes;
+        self
+    }
+
+    /// Records transactions instead of executing them, see [LocalEnvironment::recorded_transactions]. Disabled by default.
+    /// In dry-run mode, executing a transaction leaves all accounts untouched and returns a synthetic successful result without logs or balances.
+    pub fn set_dry_run(&mut self, enabled: bool) -> &mut Self {
+        self.settings.dry_run = enabled;
+        self
+    }
+
     /// Adds the account into the environment.
     pub fn add_account(&mut self, pubkey: Pubkey, account: Account) -> &mut Self {
         self.config.add_account(pubkey, account.into());
@@ -787,12 +2748,152 @@ impl LocalEnvironmentBuilder {
         )
     }
 
+    // Add a stake account into the environment. If a vote account is given, the stake is fully activated and delegated to it.
+    pub fn add_stake_account(
+        &mut self,
+        pubkey: Pubkey,
+        authorized: Authorized,
+        lockup: Lockup,
+        delegated_vote: Option<Pubkey>,
+        stake_lamports: u64,
+    ) -> &mut Self {
+        let rent_exempt_reserve = self.config.rent.minimum_balance(StakeState::size_of());
+        let meta = Meta {
+            rent_exempt_reserve,
+            authorized,
+            lockup,
+        };
+        let state = match delegated_vote {
+            Some(voter_pubkey) => StakeState::Stake(
+                meta,
+                Stake {
+                    delegation: Delegation {
+                        voter_pubkey,
+                        stake: stake_lamports,
+                        // activated since genesis
+                        activation_epoch: u64::MAX,
+                        ..Delegation::default()
+                    },
+                    credits_observed: 0,
+                },
+            ),
+            None => StakeState::Initialized(meta),
+        };
+        self.add_account(
+            pubkey,
+            Account::new_data_with_space(
+                rent_exempt_reserve + stake_lamports,
+                &state,
+                StakeState::size_of(),
+                &stake::program::ID,
+            )
+            .unwrap(),
+        )
+    }
+
+    // Add a rent-excempt vote account into the environment.
+    pub fn add_vote_account(
+        &mut self,
+        pubkey: Pubkey,
+        node: Pubkey,
+        authorized_voter: Pubkey,
+        authorized_withdrawer: Pubkey,
+        commission: u8,
+    ) -> &mut Self {
+        let account = vote_state::create_account_with_authorized(
+            &node,
+            &authorized_voter,
+            &authorized_withdrawer,
+            commission,
+            self.config.rent.minimum_balance(VoteState::size_of()),
+        );
+        self.add_account(pubkey, account.into())
+    }
+
+    /// Add a Metaplex token metadata account for the mint into the environment, with the given update authority.
+    /// The token-metadata program is not bundled, so it is cloned from the cluster using the given rpc client unless it was already added;
+    /// the client is not used otherwise. Panics if name, symbol or uri exceed the length limits of the program.
+    pub fn add_token_metadata(
+        &mut self,
+        client: &RpcClient,
+        mint: Pubkey,
+        name: String,
+        symbol: String,
+        uri: String,
+        update_authority: Pubkey,
+    ) -> &mut Self {
+        self.clone_programs_from_cluster_if_missing(client, &[TOKEN_METADATA_PROGRAM_ID]);
+
+        // Metaplex pads the strings to their maximum length and preallocates the maximum account size
+        let puffed = |field: &str, value: String, max_len: usize| {
+            assert!(
+                value.len() <= max_len,
+                "token metadata {} is {} bytes long, at most {} bytes are allowed",
+                field,
+                value.len(),
+                max_len
+            );
+            let mut value = value.into_bytes();
+            value.resize(max_len, 0);
+            value.try_to_vec().unwrap()
+        };
+        let mut data = vec![METADATA_KEY_V1];
+        data.extend_from_slice(update_authority.as_ref());
+        data.extend_from_slice(mint.as_ref());
+        data.extend(puffed("name", name, MAX_NAME_LENGTH));
+        data.extend(puffed("symbol", symbol, MAX_SYMBOL_LENGTH));
+        data.extend(puffed("uri", uri, MAX_URI_LENGTH));
+        data.extend_from_slice(&0u16.to_le_bytes()); // seller_fee_basis_points
+        data.push(0); // creators
+        data.push(0); // primary_sale_happened
+        data.push(1); // is_mutable
+        data.resize(MAX_METADATA_LEN, 0);
+
+        let (metadata, _) = Pubkey::find_program_address(
+            &[
+                b"metadata",
+                TOKEN_METADATA_PROGRAM_ID.as_ref(),
+                mint.as_ref(),
+            ],
+            &TOKEN_METADATA_PROGRAM_ID,
+        );
+        self.add_account_with_data(metadata, TOKEN_METADATA_PROGRAM_ID, &data, false)
+    }
+
+    /// Adds the accounts and programs of a `solana-program-test` `ProgramTest` setup into the environment.
+    pub fn add_program_test_accounts(
+        &mut self,
+        accounts: Vec<(Pubkey, Account)>,
+        programs: Vec<(Pubkey, PathBuf)>,
+    ) -> &mut Self {
+        for (pubkey, account) in accounts {
+            self.add_account(pubkey, account);
+        }
+        for (pubkey, path) in programs {
+            self.add_program(pubkey, path);
+        }
+        self
+    }
+
     /// Clone an account from a cluster using the given rpc client. Use [clone_upgradable_program_from_cluster] if you want to clone a upgradable program, as this requires multiple accounts.
     pub fn clone_account_from_cluster(&mut self, pubkey: Pubkey, client: &RpcClient) -> &mut Self {
-        println!("Loading account {} from cluster", pubkey);
+        self.clone_account_from_cluster_with_progress(pubkey, client, 1, 1)
+    }
+
+    fn clone_account_from_cluster_with_progress(
+        &mut self,
+        pubkey: Pubkey,
+        client: &RpcClient,
+        current: usize,
+        total: usize,
+    ) -> &mut Self {
+        if self.progress_callback.is_none() {
+            println!("Loading account {} from cluster", pubkey);
+        }
         let account = client
             .get_account(&pubkey)
             .expect("couldn't retrieve account");
+        self.report_progress(current, total, pubkey, account.data.len());
         self.add_account(
             pubkey,
             Account {
@@ -811,66 +2912,247 @@ impl LocalEnvironmentBuilder {
         pubkeys: &[Pubkey],
         client: &RpcClient,
     ) -> &mut Self {
-        for &pubkey in pubkeys {
-            self.clone_account_from_cluster(pubkey, client);
+        for (i, &pubkey) in pubkeys.iter().enumerate() {
+            self.clone_account_from_cluster_with_progress(pubkey, client, i + 1, pubkeys.len());
+        }
+        self
+    }
+
+    /// Clone the spl-token accounts of the given mint from a cluster using the given rpc client, up to the given limit.
+    pub fn clone_token_accounts_of_mint_from_cluster(
+        &mut self,
+        client: &RpcClient,
+        mint: Pubkey,
+        limit: Option<usize>,
+    ) -> &mut Self {
+        if self.progress_callback.is_none() {
+            println!("Loading token accounts of mint {} from cluster", mint);
+        }
+        let accounts = client
+            .get_program_accounts_with_config(
+                &spl_token::ID,
+                RpcProgramAccountsConfig {
+                    filters: Some(vec![
+                        RpcFilterType::DataSize(spl_token::state::Account::LEN as u64),
+                        RpcFilterType::Memcmp(Memcmp::new_base58_encoded(0, mint.as_ref())),
+                    ]),
+                    account_config: RpcAccountInfoConfig {
+                        encoding: Some(UiAccountEncoding::Base64),
+                        ..RpcAccountInfoConfig::default()
+                    },
+                    ..RpcProgramAccountsConfig::default()
+                },
+            )
+            .expect("couldn't retrieve token accounts");
+        let total = accounts.len().min(limit.unwrap_or(usize::MAX));
+        for (i, (pubkey, account)) in accounts.into_iter().take(total).enumerate() {
+            self.report_progress(i + 1, total, pubkey, account.data.len());
+            self.add_account(
+                pubkey,
+                Account {
+                    rent_epoch: 0,
+                    ..account
+                },
+            );
+        }
+        self
+    }
+
+    /// Clones all accounts required to execute the given executable program from the cluster, using the given rpc client.
+    pub fn clone_upgradable_program_from_cluster(
+        &mut self,
+        client: &RpcClient,
+        pubkey: Pubkey,
+    ) -> &mut Self {
+        if self.progress_callback.is_none() {
+            println!("Loading upgradable program {} from cluster", pubkey);
+        }
+        let account = client
+            .get_account(&pubkey)
+            .expect("couldn't retrieve account");
+        let upgradable: UpgradeableLoaderState = account.deserialize_data().unwrap();
+        if let UpgradeableLoaderState::Program {
+            programdata_address,
+        } = upgradable
+        {
+            self.report_progress(1, 2, pubkey, account.data.len());
+            self.add_account(pubkey, account);
+            self.clone_account_from_cluster_with_progress(programdata_address, client, 2, 2);
+        } else {
+            panic!("Account is not an upgradable program")
+        }
+        self
+    }
+
+    fn clone_programs_from_cluster_if_missing(&mut self, client: &RpcClient, programs: &[Pubkey]) {
+        for &program in programs {
+            if self.config.accounts.contains_key(&program) {
+                continue;
+            }
+            let account = client
+                .get_account(&program)
+                .expect("couldn't retrieve account");
+            if account.owner == bpf_loader_upgradeable::ID {
+                self.clone_upgradable_program_from_cluster(client, program);
+            } else {
+                self.clone_account_from_cluster(program, client);
+            }
+        }
+    }
+
+    fn report_progress(&self, current: usize, total: usize, pubkey: Pubkey, bytes: usize) {
+        if let Some(cb) = &self.progress_callback {
+            cb(CloneProgress {
+                current,
+                total,
+                pubkey,
+                bytes,
+            });
+        }
+    }
+
+    /// Restores an environment written with [LocalEnvironment::save_state], warping it to the saved slot and registering the saved blockhash as the latest one.
+    /// All settings except the compute budget are restored. Builtins and the compute budget are not part of the saved state and use their defaults.
+    pub fn load_state<P: AsRef<Path>>(path: P) -> io::Result<LocalEnvironment> {
+        let (config, faucet, slot, blockhash, refuel_lamports, settings): (
+            GenesisConfig,
+            Vec<u8>,
+            Slot,
+            Hash,
+            Option<u64>,
+            SavedSettings,
+        ) = bincode::deserialize_from(File::open(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let settings = Settings::from_saved(settings);
+        let mut builder = LocalEnvironmentBuilder::new();
+        builder.config = config;
+        builder.faucet = Keypair::from_bytes(&faucet)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        // the saved accounts already paid their rent, so no rent is collected while warping to the saved slot
+        builder.settings = Settings {
+            collect_rent_on_warp: false,
+            ..settings
+        };
+
+        let mut env = builder.build();
+        if slot > env.bank.slot() {
+            env = env.warp_to_slot(slot);
+        }
+        env.register_blockhash_as(&blockhash);
+        env.settings = settings;
+        env.refuel_lamports = refuel_lamports;
+        Ok(env)
+    }
+
+    /// Clones a concurrent merkle tree of the account-compression program from the cluster, together with the account-compression and noop programs it needs.
+    pub fn clone_merkle_tree_from_cluster(
+        &mut self,
+        client: &RpcClient,
+        tree: Pubkey,
+    ) -> &mut Self {
+        self.clone_programs_from_cluster_if_missing(
+            client,
+            &[SPL_ACCOUNT_COMPRESSION_ID, SPL_NOOP_ID],
+        );
+
+        let account = client
+            .get_account(&tree)
+            .expect("couldn't retrieve account");
+        assert_eq!(
+            account.owner, SPL_ACCOUNT_COMPRESSION_ID,
+            "account {} is not a merkle tree of the account-compression program",
+            tree
+        );
+        self.clone_account_from_cluster(tree, client)
+    }
+
+    /// Returns the accounts currently staged for the genesis of the environment, sorted by pubkey.
+    pub fn pending_accounts(&self) -> Vec<(Pubkey, &Account)> {
+        self.config
+            .accounts
+            .iter()
+            .map(|(pubkey, account)| (*pubkey, account))
+            .collect()
+    }
+
+    /// Prints pubkey, owner, lamports and data length of all accounts currently staged for the genesis of the environment.
+    pub fn dump_pending(&self) {
+        for (pubkey, account) in self.pending_accounts() {
+            println!(
+                "{} owner: {} lamports: {} data: {} bytes{}",
+                pubkey,
+                account.owner,
+                account.lamports,
+                account.data.len(),
+                if account.executable {
+                    " (executable)"
+                } else {
+                    ""
+                }
+            );
         }
-        self
     }
 
-    /// Clones all accounts required to execute the given executable program from the cluster, using the given rpc client.
-    pub fn clone_upgradable_program_from_cluster(
-        &mut self,
-        client: &RpcClient,
-        pubkey: Pubkey,
-    ) -> &mut Self {
-        println!("Loading upgradable program {} from cluster", pubkey);
-        let account = client
-            .get_account(&pubkey)
-            .expect("couldn't retrieve account");
-        let upgradable: UpgradeableLoaderState = account.deserialize_data().unwrap();
-        if let UpgradeableLoaderState::Program {
-            programdata_address,
-        } = upgradable
-        {
-            self.add_account(pubkey, account);
-            self.clone_account_from_cluster(programdata_address, client);
-        } else {
-            panic!("Account is not an upgradable program")
+    fn refuel_lamports(&self) -> Option<u64> {
+        if !self.auto_refuel {
+            return None;
         }
-        self
+        self.config
+            .accounts
+            .get(&self.faucet.pubkey())
+            .map(|faucet| faucet.lamports)
     }
 
     /// Finalizes the environment.
     pub fn build(&mut self) -> LocalEnvironment {
-        let tmpdir = Path::new("/tmp/");
-        let exit = Arc::new(AtomicBool::new(false));
-        let bank = Bank::new_with_paths(
-            &self.config,
-            Arc::new(RuntimeConfig::default()),
-            vec![tmpdir.to_path_buf()],
-            None,
-            None,
-            AccountSecondaryIndexes {
-                keys: None,
-                indexes: HashSet::new(),
-            },
-            AccountShrinkThreshold::default(),
-            false,
-            None,
-            None,
-            &exit,
+        let env = LocalEnvironment::from_genesis(
+            self.config.clone(),
+            clone_keypair(&self.faucet),
+            self.builtins.clone(),
+            self.refuel_lamports(),
+            self.settings,
         );
-
-        let env = LocalEnvironment {
-            bank,
-            faucet: clone_keypair(&self.faucet),
-        };
+        for (pubkey, account) in &self.sysvars {
+            env.bank.store_account(pubkey, account);
+        }
         env.advance_blockhash();
 
         env
     }
 }
 
+fn new_bank(
+    config: &GenesisConfig,
+    compute_budget: Option<ComputeBudget>,
+    builtins: &[(String, Pubkey, ProcessInstructionWithContext)],
+) -> Bank {
+    let tmpdir = Path::new("/tmp/");
+    let exit = Arc::new(AtomicBool::new(false));
+    let mut bank = Bank::new_with_paths(
+        config,
+        Arc::new(RuntimeConfig {
+            compute_budget,
+            ..RuntimeConfig::default()
+        }),
+        vec![tmpdir.to_path_buf()],
+        None,
+        None,
+        AccountSecondaryIndexes {
+            keys: None,
+            indexes: HashSet::new(),
+        },
+        AccountShrinkThreshold::default(),
+        false,
+        None,
+        None,
+        &exit,
+    );
+    for (name, program_id, entrypoint) in builtins {
+        bank.add_builtin(name, program_id, *entrypoint);
+    }
+    bank
+}
+
 /// A remote environment on a cluster. Interacts with the cluster using RPC.
 pub struct RemoteEnvironment {
     client: RpcClient,
@@ -890,6 +3172,11 @@ impl RemoteEnvironment {
         env
     }
 
+    /// The rpc client used to talk to the cluster, for requests not covered by [Environment].
+    pub fn client(&self) -> &RpcClient {
+        &self.client
+    }
+
     /// Airdrop lamports up to the given balance to the account.
     pub fn airdrop(&self, account: Pubkey, lamports: u64) {
         if self.client.get_balance(&account).expect("get balance") < lamports {
@@ -904,6 +3191,68 @@ impl RemoteEnvironment {
                 .unwrap();
         }
     }
+
+    /// Subscribes to the transaction logs matching the filter and returns the signature of the first transaction with a log line containing the pattern.
+    /// Uses the websocket endpoint next to the rpc endpoint of the client. Returns None if no matching transaction was seen before the timeout.
+    pub fn watch_logs(
+        &self,
+        filter: RpcTransactionLogsFilter,
+        pattern: &str,
+        timeout: Duration,
+    ) -> Option<Signature> {
+        let (mut subscription, receiver) = PubsubClient::logs_subscribe(
+            &websocket_url(&self.client.url()).expect("rpc url has no websocket counterpart"),
+            filter,
+            RpcTransactionLogsConfig {
+                commitment: Some(CommitmentConfig::confirmed()),
+            },
+        )
+        .expect("couldn't subscribe to logs");
+
+        let deadline = Instant::now() + timeout;
+        let mut signature = None;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            match receiver.recv_timeout(remaining) {
+                Ok(response) => {
+                    if response.value.logs.iter().any(|log| log.contains(pattern)) {
+                        signature = response.value.signature.parse().ok();
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        let _ = subscription.shutdown();
+        signature
+    }
+
+    /// Returns the up to 20 largest token accounts of the given mint with their balances, largest first.
+    pub fn get_token_largest_accounts(&self, mint: Pubkey) -> Vec<(Pubkey, u64)> {
+        self.client
+            .get_token_largest_accounts(&mint)
+            .expect("couldn't retrieve largest token accounts")
+            .into_iter()
+            .map(|balance| {
+                (
+                    balance.address.parse().unwrap(),
+                    balance.amount.amount.parse().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    /// Returns the prioritization fees paid in recent slots by transactions locking all of the given accounts, as `(slot, micro-lamports per CU)`.
+    /// Returns an empty vec if the rpc node does not support the request.
+    pub fn get_recent_prioritization_fees(&self, accounts: &[Pubkey]) -> Vec<(Slot, u64)> {
+        self.client
+            .get_recent_prioritization_fees(accounts)
+            .map(|fees| {
+                fees.into_iter()
+                    .map(|fee| (fee.slot, fee.prioritization_fee))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
 }
 
 impl Environment for RemoteEnvironment {
@@ -933,6 +3282,31 @@ impl Environment for RemoteEnvironment {
             .unwrap()
     }
 
+    fn send_transaction<T>(&mut self, tx: T) -> Signature
+    where
+        VersionedTransaction: From<T>,
+    {
+        let tx = VersionedTransaction::from(tx);
+        match self.client.send_transaction(&tx) {
+            Err(e) => panic!("{:#?}", e),
+            Ok(sig) => sig,
+        }
+    }
+
+    fn confirm(&self, signature: Signature) -> Result<(), TransactionError> {
+        self.client
+            .poll_for_signature_with_commitment(&signature, CommitmentConfig::confirmed())
+            .expect("transaction was not confirmed");
+        self.client
+            .get_signature_status_with_commitment(&signature, CommitmentConfig::confirmed())
+            .unwrap()
+            .expect("transaction was not confirmed")
+    }
+
+    fn get_signature_status(&self, signature: Signature) -> Option<Result<(), TransactionError>> {
+        self.client.get_signature_status(&signature).unwrap()
+    }
+
     fn get_latest_blockhash(&self) -> Hash {
         self.client.get_latest_blockhash().unwrap()
     }
@@ -943,18 +3317,262 @@ impl Environment for RemoteEnvironment {
             .unwrap()
     }
 
+    fn get_rent(&self) -> Rent {
+        let account = self
+            .get_account(rent::ID)
+            .expect("rent sysvar does not exist");
+        bincode::deserialize(&account.data).expect("failed to deserialize rent sysvar")
+    }
+
     fn get_account(&self, pubkey: Pubkey) -> Option<Account> {
+        self.get_account_with_commitment(pubkey, self.client.commitment())
+    }
+
+    fn get_account_with_commitment(
+        &self,
+        pubkey: Pubkey,
+        commitment: CommitmentConfig,
+    ) -> Option<Account> {
         self.client
-            .get_account_with_commitment(&pubkey, self.client.commitment())
+            .get_account_with_commitment(&pubkey, commitment)
             .unwrap()
             .value
     }
+
+    fn get_epoch_info(&self) -> EpochInfo {
+        self.client.get_epoch_info().unwrap()
+    }
+
+    fn get_block_height(&self) -> u64 {
+        self.client.get_block_height().unwrap()
+    }
+
+    fn estimate_fee(&self, message: &Message) -> u64 {
+        self.client.get_fee_for_message(message).unwrap()
+    }
+
+    fn get_minimum_stake_delegation(&self) -> u64 {
+        self.client.get_stake_minimum_delegation().unwrap()
+    }
+
+    fn airdrop(&mut self, account: Pubkey, lamports: u64) {
+        RemoteEnvironment::airdrop(self, account, lamports)
+    }
+}
+
+/// The results of an executed transaction as plain Rust types.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecutionOutcome {
+    pub status: Result<(), TransactionError>,
+    pub logs: Vec<String>,
+    pub compute_units_consumed: Option<u64>,
+    pub inner_instructions: Vec<InnerInstructions>,
+    pub return_data: Option<TransactionReturnData>,
+}
+
+impl From<&EncodedConfirmedTransactionWithStatusMeta> for ExecutionOutcome {
+    fn from(tx: &EncodedConfirmedTransactionWithStatusMeta) -> Self {
+        let meta = match &tx.transaction.meta {
+            Some(meta) => meta,
+            None => {
+                return ExecutionOutcome {
+                    status: Ok(()),
+                    logs: vec![],
+                    compute_units_consumed: None,
+                    inner_instructions: vec![],
+                    return_data: None,
+                }
+            }
+        };
+
+        let inner_instructions: Option<Vec<UiInnerInstructions>> =
+            meta.inner_instructions.clone().into();
+        let return_data: Option<UiTransactionReturnData> = meta.return_data.clone().into();
+        ExecutionOutcome {
+            status: meta.err.clone().map_or(Ok(()), Err),
+            logs: Option::from(meta.log_messages.clone()).unwrap_or_default(),
+            compute_units_consumed: meta.compute_units_consumed.clone().into(),
+            inner_instructions: inner_instructions
+                .unwrap_or_default()
+                .iter()
+                .map(decode_inner_instructions)
+                .collect(),
+            return_data: return_data.map(|return_data| TransactionReturnData {
+                program_id: return_data.program_id.parse().unwrap(),
+                data: base64::engine::general_purpose::STANDARD
+                    .decode(return_data.data.0)
+                    .unwrap(),
+            }),
+        }
+    }
+}
+
+/// Decodes the inner instructions of a binary encoded transaction.
+fn decode_inner_instructions(inner: &UiInnerInstructions) -> InnerInstructions {
+    InnerInstructions {
+        index: inner.index,
+        instructions: inner
+            .instructions
+            .iter()
+            .map(|ix| match ix {
+                UiInstruction::Compiled(ix) => InnerInstruction {
+                    instruction: CompiledInstruction {
+                        program_id_index: ix.program_id_index,
+                        accounts: ix.accounts.clone(),
+                        data: bs58::decode(&ix.data).into_vec().unwrap(),
+                    },
+                    stack_height: ix.stack_height,
+                },
+                UiInstruction::Parsed(_) => panic!("parsed inner instructions are not supported"),
+            })
+            .collect(),
+    }
+}
+
+/// Decodes the status meta of a binary encoded transaction.
+fn decode_status_meta(meta: &UiTransactionStatusMeta) -> TransactionStatusMeta {
+    let inner_instructions: Option<Vec<UiInnerInstructions>> =
+        meta.inner_instructions.clone().into();
+    let loaded_addresses: Option<UiLoadedAddresses> = meta.loaded_addresses.clone().into();
+    let return_data: Option<UiTransactionReturnData> = meta.return_data.clone().into();
+    TransactionStatusMeta {
+        status: meta.err.clone().map_or(Ok(()), Err),
+        fee: meta.fee,
+        pre_balances: meta.pre_balances.clone(),
+        post_balances: meta.post_balances.clone(),
+        inner_instructions: inner_instructions
+            .map(|inner| inner.iter().map(decode_inner_instructions).collect()),
+        log_messages: meta.log_messages.clone().into(),
+        pre_token_balances: decode_token_balances(meta.pre_token_balances.clone().into()),
+        post_token_balances: decode_token_balances(meta.post_token_balances.clone().into()),
+        rewards: meta.rewards.clone().into(),
+        loaded_addresses: loaded_addresses
+            .map(|loaded| LoadedAddresses {
+                writable: loaded.writable.iter().map(|k| k.parse().unwrap()).collect(),
+                readonly: loaded.readonly.iter().map(|k| k.parse().unwrap()).collect(),
+            })
+            .unwrap_or_default(),
+        return_data: return_data.map(|return_data| TransactionReturnData {
+            program_id: return_data.program_id.parse().unwrap(),
+            data: base64::engine::general_purpose::STANDARD
+                .decode(return_data.data.0)
+                .unwrap(),
+        }),
+        compute_units_consumed: meta.compute_units_consumed.clone().into(),
+    }
+}
+
+fn decode_token_balances(
+    balances: Option<Vec<UiTransactionTokenBalance>>,
+) -> Option<Vec<TransactionTokenBalance>> {
+    balances.map(|balances| {
+        balances
+            .into_iter()
+            .map(|balance| TransactionTokenBalance {
+                account_index: balance.account_index,
+                mint: balance.mint,
+                ui_token_amount: balance.ui_token_amount,
+                owner: Option::from(balance.owner).unwrap_or_default(),
+                program_id: Option::from(balance.program_id).unwrap_or_default(),
+            })
+            .collect()
+    })
+}
+
+/// Options controlling how [PrintableTransaction::print_with_options] prints a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrintOptions {
+    /// Highlight the header in green or red, depending on the transaction status.
+    pub color: bool,
+    /// Number of spaces the transaction details are indented with.
+    pub indent: usize,
+    pub show_logs: bool,
+    pub show_balances: bool,
+    /// Names of custom program error codes, e.g. extracted from an Anchor IDL. Matching errors are annotated with their name.
+    pub anchor_errors: HashMap<u32, String>,
+}
+
+impl Default for PrintOptions {
+    /// Prints everything, using color unless the `NO_COLOR` environment variable is set.
+    fn default() -> Self {
+        PrintOptions {
+            color: std::env::var_os("NO_COLOR").is_none(),
+            indent: 2,
+            show_logs: true,
+            show_balances: true,
+            anchor_errors: HashMap::new(),
+        }
+    }
+}
+
+impl PrintOptions {
+    fn println(
+        &self,
+        name: &str,
+        slot: u64,
+        tx: &VersionedTransaction,
+        meta: Option<UiTransactionStatusMeta>,
+    ) {
+        let meta = meta.map(|mut meta| {
+            if !self.show_logs {
+                meta.log_messages = OptionSerializer::None;
+            }
+            if !self.show_balances {
+                meta.pre_balances.clear();
+                meta.post_balances.clear();
+                meta.pre_token_balances = OptionSerializer::None;
+                meta.post_token_balances = OptionSerializer::None;
+            }
+            meta
+        });
+
+        let header = format!("EXECUTE {} (slot {})", name, slot);
+        if self.color {
+            let failed = meta.as_ref().map_or(false, |meta| meta.err.is_some());
+            let color = if failed { 31 } else { 32 };
+            println!("\x1b[1;{}m{}\x1b[0m", color, header);
+        } else {
+            println!("{}", header);
+        }
+        println_transaction(tx, meta.as_ref(), &" ".repeat(self.indent), None, None);
+
+        if let Some(TransactionError::InstructionError(index, InstructionError::Custom(code))) =
+            meta.and_then(|meta| meta.err)
+        {
+            if let Some(name) = self.anchor_errors.get(&code) {
+                println!(
+                    "{}Instruction {} failed with Anchor error {} ({})",
+                    " ".repeat(self.indent),
+                    index,
+                    name,
+                    code
+                );
+            }
+        }
+    }
 }
 
 /// Utility trait for printing transaction results.
 pub trait PrintableTransaction {
     /// Pretty print the transaction results, tagged with the given name for distinguishability.
-    fn print_named(&self, name: &str);
+    fn print_named(&self, name: &str) {
+        self.print_with_options(name, &PrintOptions::default());
+    }
+
+    /// Pretty print the transaction results, tagged with the given name, using the given options.
+    fn print_with_options(&self, name: &str, options: &PrintOptions);
+
+    /// Pretty print the transaction results, annotating custom program errors with the given error names.
+    /// The mapping from error code to name is usually extracted from the `errors` section of an Anchor IDL.
+    fn with_anchor_errors(&self, idl_errors: &HashMap<u32, String>) {
+        self.print_with_options(
+            "",
+            &PrintOptions {
+                anchor_errors: idl_errors.clone(),
+                ..PrintOptions::default()
+            },
+        );
+    }
 
     /// Pretty print the transaction results.
     fn print(&self) {
@@ -963,17 +3581,193 @@ pub trait PrintableTransaction {
 
     /// Panic and print the transaction if it did not execute successfully
     fn assert_success(&self);
+
+    /// Panic and print the transaction if it did not execute successfully, otherwise return it for chaining.
+    fn expect_success(&self) -> &Self {
+        self.assert_success();
+        self
+    }
+
+    /// Panic and print the transaction if it executed successfully
+    fn assert_failed(&self);
+
+    /// Panic and print the transaction if it executed successfully, otherwise return it for chaining.
+    fn expect_failed(&self) -> &Self {
+        self.assert_failed();
+        self
+    }
+
+    /// The error the transaction failed with. None if it executed successfully.
+    fn error(&self) -> Option<TransactionError>;
+
+    /// Panic and print the transaction if the lamports of all accounts before and after the transaction differ by anything but the fee.
+    fn assert_lamports_conserved(&self, expected_fee: u64);
+
+    /// Panic and print the transaction if the instruction at the given index did not fail with the given custom program error.
+    fn assert_custom_error(&self, instruction_index: u8, code: u32) {
+        let expected =
+            TransactionError::InstructionError(instruction_index, InstructionError::Custom(code));
+        let actual = self.error();
+        if actual.as_ref() != Some(&expected) {
+            self.print();
+            panic!("expected error {:?}, got {:?}", expected, actual)
+        }
+    }
+
+    /// Write the transaction and its status meta as JSON into the given file.
+    fn write_json<P: AsRef<Path>>(&self, path: P) -> io::Result<()>;
+
+    /// Link to the transaction on the Solana Explorer. None if the transaction has no signature.
+    fn explorer_url(&self, cluster: ClusterType) -> Option<String>;
+
+    /// Decodes the inner instructions, grouped by the index of the top-level instruction that invoked them.
+    /// Account privileges are taken from the transaction message, since CPIs do not record them.
+    fn inner_instructions(&self) -> Vec<(u8, Vec<Instruction>)>;
+
+    /// The maximum stack height reached by the transaction. Top-level instructions run at height 1, every nested CPI adds one.
+    fn max_cpi_depth(&self) -> u32;
+}
+
+fn decompile_inner_instructions(
+    message: &VersionedMessage,
+    loaded_addresses: &LoadedAddresses,
+    inner_instructions: &[InnerInstructions],
+) -> Vec<(u8, Vec<Instruction>)> {
+    let static_keys = message.static_account_keys();
+    let num_signers = message.header().num_required_signatures as usize;
+    let account_meta = |index: u8| {
+        let index = index as usize;
+        if index < static_keys.len() {
+            AccountMeta {
+                pubkey: static_keys[index],
+                is_signer: index < num_signers,
+                is_writable: message.is_maybe_writable(index),
+            }
+        } else if index - static_keys.len() < loaded_addresses.writable.len() {
+            AccountMeta::new(loaded_addresses.writable[index - static_keys.len()], false)
+        } else {
+            AccountMeta::new_readonly(
+                loaded_addresses.readonly
+                    [index - static_keys.len() - loaded_addresses.writable.len()],
+                false,
+            )
+        }
+    };
+
+    inner_instructions
+        .iter()
+        .map(|inner| {
+            let instructions = inner
+                .instructions
+                .iter()
+                .map(|ix| Instruction {
+                    program_id: account_meta(ix.instruction.program_id_index).pubkey,
+                    accounts: ix
+                        .instruction
+                        .accounts
+                        .iter()
+                        .map(|&index| account_meta(index))
+                        .collect(),
+                    data: ix.instruction.data.clone(),
+                })
+                .collect();
+            (inner.index, instructions)
+        })
+        .collect()
+}
+
+fn assert_lamports_conserved<T: PrintableTransaction>(
+    tx: &T,
+    pre_balances: &[u64],
+    post_balances: &[u64],
+    expected_fee: u64,
+) {
+    let pre = pre_balances.iter().map(|&b| b as i128).sum::<i128>();
+    let post = post_balances.iter().map(|&b| b as i128).sum::<i128>();
+    if pre - post != expected_fee as i128 {
+        tx.print();
+        panic!(
+            "lamports not conserved: {} before, {} after, expected fee {}",
+            pre, post, expected_fee
+        )
+    }
+}
+
+fn max_stack_height(inner: &[InnerInstructions]) -> u32 {
+    inner
+        .iter()
+        .flat_map(|inner| inner.instructions.iter())
+        // inner instructions recorded before stack heights were tracked are at least one level deep
+        .map(|ix| ix.stack_height.unwrap_or(2))
+        .max()
+        .unwrap_or(1)
+}
+
+fn explorer_url(signature: &Signature, cluster: ClusterType) -> String {
+    let query = match cluster {
+        ClusterType::MainnetBeta => "",
+        ClusterType::Testnet => "?cluster=testnet",
+        ClusterType::Devnet => "?cluster=devnet",
+        ClusterType::Development => "?cluster=custom",
+    };
+    format!("https://explorer.solana.com/tx/{}{}", signature, query)
 }
 
 impl PrintableTransaction for ConfirmedTransactionWithStatusMeta {
-    fn print_named(&self, name: &str) {
+    fn print_with_options(&self, name: &str, options: &PrintOptions) {
         let tx = self.tx_with_meta.get_transaction();
         let encoded = self
             .clone()
             .encode(UiTransactionEncoding::JsonParsed, None)
             .expect("Failed to encode");
-        println!("EXECUTE {} (slot {})", name, encoded.slot);
-        println_transaction(&tx, encoded.transaction.meta.as_ref(), "  ", None, None);
+        options.println(name, encoded.slot, &tx, encoded.transaction.meta);
+    }
+
+    fn write_json<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let encoded = self
+            .clone()
+            .encode(UiTransactionEncoding::JsonParsed, Some(0))
+            .expect("Failed to encode");
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, &encoded)?;
+        Ok(())
+    }
+
+    fn explorer_url(&self, cluster: ClusterType) -> Option<String> {
+        let tx = self.tx_with_meta.get_transaction();
+        let signature = tx.signatures.first()?;
+        Some(explorer_url(signature, cluster))
+    }
+
+    fn inner_instructions(&self) -> Vec<(u8, Vec<Instruction>)> {
+        let tx = self.tx_with_meta.get_transaction();
+        match self.tx_with_meta.get_status_meta() {
+            Some(meta) => decompile_inner_instructions(
+                &tx.message,
+                &meta.loaded_addresses,
+                &meta.inner_instructions.unwrap_or_default(),
+            ),
+            None => vec![],
+        }
+    }
+
+    fn max_cpi_depth(&self) -> u32 {
+        match self.tx_with_meta.get_status_meta() {
+            Some(meta) => max_stack_height(&meta.inner_instructions.unwrap_or_default()),
+            None => 1,
+        }
+    }
+
+    fn error(&self) -> Option<TransactionError> {
+        self.tx_with_meta
+            .get_status_meta()
+            .and_then(|meta| meta.status.err())
+    }
+
+    fn assert_lamports_conserved(&self, expected_fee: u64) {
+        if let Some(meta) = self.tx_with_meta.get_status_meta() {
+            assert_lamports_conserved(self, &meta.pre_balances, &meta.post_balances, expected_fee);
+        }
     }
 
     fn assert_success(&self) {
@@ -985,13 +3779,89 @@ impl PrintableTransaction for ConfirmedTransactionWithStatusMeta {
             _ => (),
         }
     }
+
+    fn assert_failed(&self) {
+        match &self.tx_with_meta.get_status_meta() {
+            Some(meta) if meta.status.is_err() => (),
+            _ => {
+                self.print();
+                panic!("tx succeeded!")
+            }
+        }
+    }
 }
 
 impl PrintableTransaction for EncodedConfirmedTransactionWithStatusMeta {
-    fn print_named(&self, name: &str) {
+    fn print_with_options(&self, name: &str, options: &PrintOptions) {
         let tx = self.transaction.transaction.decode().unwrap();
-        println!("EXECUTE {} (slot {})", name, self.slot);
-        println_transaction(&tx, self.transaction.meta.as_ref(), "  ", None, None);
+        options.println(name, self.slot, &tx, self.transaction.meta.clone());
+    }
+
+    /// Binary encoded transactions are decoded and written with the JsonParsed encoding, like [ConfirmedTransactionWithStatusMeta::write_json].
+    /// Transactions that were already fetched as JSON are written as they are.
+    fn write_json<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let transaction = self.transaction.transaction.decode();
+        let tx_with_meta = match (transaction, &self.transaction.meta) {
+            (Some(transaction), Some(meta)) => {
+                TransactionWithStatusMeta::Complete(VersionedTransactionWithStatusMeta {
+                    transaction,
+                    meta: decode_status_meta(meta),
+                })
+            }
+            _ => {
+                let file = File::create(path)?;
+                serde_json::to_writer_pretty(file, self)?;
+                return Ok(());
+            }
+        };
+        ConfirmedTransactionWithStatusMeta {
+            slot: self.slot,
+            tx_with_meta,
+            block_time: self.block_time,
+        }
+        .write_json(path)
+    }
+
+    fn explorer_url(&self, cluster: ClusterType) -> Option<String> {
+        let tx = self.transaction.transaction.decode()?;
+        let signature = tx.signatures.first()?;
+        Some(explorer_url(signature, cluster))
+    }
+
+    fn inner_instructions(&self) -> Vec<(u8, Vec<Instruction>)> {
+        let tx = self.transaction.transaction.decode().unwrap();
+        let loaded_addresses = self
+            .transaction
+            .meta
+            .as_ref()
+            .and_then(|meta| Option::<UiLoadedAddresses>::from(meta.loaded_addresses.clone()))
+            .map(|loaded| LoadedAddresses {
+                writable: loaded.writable.iter().map(|k| k.parse().unwrap()).collect(),
+                readonly: loaded.readonly.iter().map(|k| k.parse().unwrap()).collect(),
+            })
+            .unwrap_or_default();
+        decompile_inner_instructions(
+            &tx.message,
+            &loaded_addresses,
+            &ExecutionOutcome::from(self).inner_instructions,
+        )
+    }
+
+    fn max_cpi_depth(&self) -> u32 {
+        max_stack_height(&ExecutionOutcome::from(self).inner_instructions)
+    }
+
+    fn error(&self) -> Option<TransactionError> {
+        self.transaction
+            .meta
+            .as_ref()
+            .and_then(|meta| meta.err.clone())
+    }
+
+    fn assert_lamports_conserved(&self, expected_fee: u64) {
+        if let Some(meta) = &self.transaction.meta {
+            assert_lamports_conserved(self, &meta.pre_balances, &meta.post_balances, expected_fee);
+        }
     }
 
     fn assert_success(&self) {
@@ -1003,6 +3873,16 @@ impl PrintableTransaction for EncodedConfirmedTransactionWithStatusMeta {
             _ => (),
         }
     }
+
+    fn assert_failed(&self) {
+        match &self.transaction.meta {
+            Some(meta) if meta.err.is_some() => (),
+            _ => {
+                self.print();
+                panic!("tx succeeded!")
+            }
+        }
+    }
 }
 
 pub enum LogLevel {
@@ -1011,37 +3891,215 @@ pub enum LogLevel {
     INFO,
     WARN,
     ERROR,
+    OFF,
 }
 
 /// Setup solana logging. This is heavily recommended if you're using a local environment.
 pub fn setup_logging(level: LogLevel) {
     match level {
-        LogLevel::TRACE => solana_logger::setup_with_default(
+        LogLevel::TRACE => setup_logging_with_filter(
             "trace,solana_runtime::message_processor=trace,solana_metrics::metrics=error",
         ),
-        LogLevel::DEBUG => solana_logger::setup_with_default(
+        LogLevel::DEBUG => setup_logging_with_filter(
             "debug,solana_runtime::message_processor=debug,solana_metrics::metrics=error",
         ),
-        LogLevel::INFO => solana_logger::setup_with_default(
+        LogLevel::INFO => setup_logging_with_filter(
             "info,solana_runtime::message_processor=info,solana_metrics::metrics=error",
         ),
-        LogLevel::WARN => solana_logger::setup_with_default(
+        LogLevel::WARN => setup_logging_with_filter(
             "warn,solana_runtime::message_processor=warn,solana_metrics::metrics=error",
         ),
-        LogLevel::ERROR => solana_logger::setup_with_default(
+        LogLevel::ERROR => setup_logging_with_filter(
             "error,solana_runtime::message_processor=error,solana_metrics::metrics=error",
         ),
+        LogLevel::OFF => setup_logging_with_filter("off"),
+    }
+}
+
+/// Setup solana logging with custom `RUST_LOG`-style directives, e.g. `"warn,solana_runtime::message_processor=trace"`.
+/// Logging is only set up once, subsequent calls are ignored.
+pub fn setup_logging_with_filter(directives: &str) {
+    static LOGGING: Once = Once::new();
+    LOGGING.call_once(|| solana_logger::setup_with_default(directives));
+}
+
+/// Unpacks a spl-token or token-2022 mint. Extensions of token-2022 mints are ignored.
+fn unpack_mint(account: &Account) -> Option<spl_token::state::Mint> {
+    if account.owner == spl_token_2022::ID {
+        let mint = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&account.data)
+            .ok()?
+            .base;
+        Some(spl_token::state::Mint {
+            mint_authority: mint.mint_authority,
+            supply: mint.supply,
+            decimals: mint.decimals,
+            is_initialized: mint.is_initialized,
+            freeze_authority: mint.freeze_authority,
+        })
+    } else if account.owner == spl_token::ID {
+        spl_token::state::Mint::unpack(&account.data).ok()
+    } else {
+        None
     }
 }
 
+/// Sums up the lamports the system instructions of the message transfer out of the given account.
+fn lamports_moved_from(message: &Message, from: Pubkey) -> u128 {
+    system_transfers(message)
+        .into_iter()
+        .filter(|&(source, _, _)| source == from)
+        .map(|(_, _, lamports)| lamports as u128)
+        .sum()
+}
+
+/// The lamports moved by the transfer and account creation instructions of the system program in the message, as `(from, to, lamports)`.
+fn system_transfers(message: &Message) -> Vec<(Pubkey, Pubkey, u64)> {
+    message
+        .instructions
+        .iter()
+        .filter(|ix| message.account_keys[ix.program_id_index as usize] == system_program::ID)
+        .filter(|ix| ix.accounts.len() >= 2)
+        .filter_map(|ix| {
+            let lamports = match bincode::deserialize(&ix.data) {
+                Ok(SystemInstruction::Transfer { lamports })
+                | Ok(SystemInstruction::CreateAccount { lamports, .. })
+                | Ok(SystemInstruction::CreateAccountWithSeed { lamports, .. }) => lamports,
+                _ => return None,
+            };
+            let key = |i: usize| message.account_keys[ix.accounts[i] as usize];
+            Some((key(0), key(1), lamports))
+        })
+        .collect()
+}
+
+/// Returns the token program owning the given mint account. Defaults to spl-token if the mint does not exist.
+fn token_program_of_mint(mint: Option<Account>) -> Pubkey {
+    match mint {
+        Some(mint) if mint.owner == spl_token_2022::ID => spl_token_2022::ID,
+        _ => spl_token::ID,
+    }
+}
+
+/// A transaction fetched from a cluster, together with the accounts it references.
+#[derive(Debug, Clone)]
+pub struct ReplayTransaction {
+    /// Slot the transaction was executed in.
+    pub slot: u64,
+    pub transaction: VersionedTransaction,
+    /// All accounts referenced by the transaction, including loaded addresses and program data accounts.
+    pub accounts: Vec<(Pubkey, Account)>,
+}
+
+/// Fetches the transaction with the given signature and all accounts it references from the cluster.
+/// RPC nodes only serve the latest account state, so the accounts reflect the current state of the cluster instead of the state before the transaction.
+pub fn fetch_transaction_for_replay(client: &RpcClient, signature: Signature) -> ReplayTransaction {
+    let encoded = client
+        .get_transaction_with_config(
+            &signature,
+            RpcTransactionConfig {
+                encoding: Some(UiTransactionEncoding::Binary),
+                commitment: Some(CommitmentConfig::confirmed()),
+                max_supported_transaction_version: Some(0),
+                ..RpcTransactionConfig::default()
+            },
+        )
+        .expect("couldn't retrieve transaction");
+    let transaction = encoded
+        .transaction
+        .transaction
+        .decode()
+        .expect("couldn't decode transaction");
+
+    let mut pubkeys = transaction.message.static_account_keys().to_vec();
+    if let Some(loaded) = encoded
+        .transaction
+        .meta
+        .and_then(|meta| Option::<UiLoadedAddresses>::from(meta.loaded_addresses))
+    {
+        pubkeys.extend(
+            loaded
+                .writable
+                .iter()
+                .chain(loaded.readonly.iter())
+                .map(|k| k.parse::<Pubkey>().unwrap()),
+        );
+    }
+
+    let mut accounts = vec![];
+    while !pubkeys.is_empty() {
+        let chunk = pubkeys.split_off(pubkeys.len().saturating_sub(100));
+        let fetched = client
+            .get_multiple_accounts(&chunk)
+            .expect("couldn't retrieve accounts");
+        for (pubkey, account) in chunk.into_iter().zip(fetched) {
+            let account = match account {
+                Some(account) => account,
+                None => continue,
+            };
+            if account.owner == bpf_loader_upgradeable::ID {
+                if let Ok(UpgradeableLoaderState::Program {
+                    programdata_address,
+                }) = account.deserialize_data()
+                {
+                    pubkeys.push(programdata_address);
+                }
+            }
+            accounts.push((pubkey, account));
+        }
+    }
+    accounts.sort_by_key(|(pubkey, _)| *pubkey);
+    accounts.dedup_by_key(|(pubkey, _)| *pubkey);
+
+    ReplayTransaction {
+        slot: encoded.slot,
+        transaction,
+        accounts,
+    }
+}
+
+/// Derives the websocket url of a node from its rpc url, which by convention uses the next port.
+/// Mirrors `solana_cli_config::compute_websocket_url`, but returns None instead of panicking if the url can not be converted.
+fn websocket_url(rpc_url: &str) -> Option<String> {
+    let rpc_url = Url::parse(rpc_url).ok()?;
+    let mut ws_url = rpc_url.clone();
+    let scheme = if rpc_url.scheme().eq_ignore_ascii_case("https") {
+        "wss"
+    } else {
+        "ws"
+    };
+    ws_url.set_scheme(scheme).ok()?;
+    if let Some(port) = rpc_url.port() {
+        ws_url.set_port(Some(port.checked_add(1)?)).ok()?;
+    }
+    Some(ws_url.to_string())
+}
+
 /// Clone the given keypair.
 pub fn clone_keypair(keypair: &Keypair) -> Keypair {
     Keypair::from_bytes(&keypair.to_bytes()).unwrap()
 }
 
-/// Generate a random keypair.
+thread_local! {
+    static SEEDED_RNG: RefCell<Option<StdRng>> = RefCell::new(None);
+}
+
+/// Generate a random keypair. Uses OS entropy unless a seed was set with [set_global_seed].
 pub fn random_keypair() -> Keypair {
-    Keypair::generate(&mut OsRng::default())
+    SEEDED_RNG.with(|rng| match rng.borrow_mut().as_mut() {
+        Some(rng) => seeded_keypair(rng),
+        None => Keypair::generate(&mut OsRng::default()),
+    })
+}
+
+/// Generate a keypair from the given rng.
+pub fn seeded_keypair(rng: &mut StdRng) -> Keypair {
+    Keypair::generate(rng)
+}
+
+/// Make all keypairs generated by [random_keypair] on the current thread deterministic.
+/// Useful to replay a failing run of a randomized PoC.
+pub fn set_global_seed(seed: u64) {
+    SEEDED_RNG.with(|rng| *rng.borrow_mut() = Some(StdRng::seed_from_u64(seed)));
 }
 
 /// Return a recognisable Keypair. The public key will start with `Kxxx`, where xxx are the three digits of the number.
@@ -0,0 +1,363 @@
+use std::convert::TryInto;
+
+use poc_framework::{
+    bincode, random_keypair,
+    solana_program_runtime::{compute_budget::ComputeBudget, invoke_context::InvokeContext},
+    solana_sdk::{
+        ed25519_instruction::new_ed25519_instruction,
+        epoch_schedule::EpochSchedule,
+        hash::Hash,
+        instruction::{AccountMeta, Instruction, InstructionError},
+        native_token::sol_to_lamports,
+        pubkey::Pubkey,
+        signature::Signature,
+        signer::Signer,
+        system_program, system_transaction,
+        sysvar::{self, slot_hashes::SlotHashes},
+        transaction::TransactionError,
+    },
+    spl_associated_token_account, spl_memo, spl_token, Environment, LocalEnvironment,
+    LocalEnvironmentBuilder, PrintableTransaction,
+};
+
+#[test]
+fn lowered_compute_unit_limit_exceeds_budget() {
+    let memo = spl_memo::build_memo(b"poc-framework", &[]);
+
+    let mut env = LocalEnvironment::new();
+    env.execute_as_transaction(&[memo.clone()], &[])
+        .assert_success();
+
+    let mut env = LocalEnvironment::builder()
+        .set_compute_budget(ComputeBudget {
+            compute_unit_limit: 10,
+            ..ComputeBudget::default()
+        })
+        .build();
+    let result = env.execute_as_transaction(&[memo], &[]);
+    result.assert_failed();
+    assert!(matches!(
+        result.error(),
+        Some(TransactionError::InstructionError(
+            0,
+            InstructionError::ProgramFailedToComplete
+                | InstructionError::ComputationalBudgetExceeded
+        ))
+    ));
+}
+
+/// Consumes the number of compute units given as little-endian u64 in the instruction data.
+fn burn_compute_units(invoke_context: &mut InvokeContext) -> Result<(), InstructionError> {
+    let transaction_context = &invoke_context.transaction_context;
+    let instruction_context = transaction_context.get_current_instruction_context()?;
+    let data = instruction_context.get_instruction_data();
+    let units = u64::from_le_bytes(
+        data.try_into()
+            .map_err(|_| InstructionError::InvalidInstructionData)?,
+    );
+    invoke_context
+        .consume_checked(units)
+        .map_err(|_| InstructionError::ComputationalBudgetExceeded)
+}
+
+#[test]
+fn raised_compute_unit_limit_allows_expensive_instruction() {
+    let burner = Pubkey::new_unique();
+    let mut env = LocalEnvironment::builder()
+        .add_builtin("compute_burner", burner, burn_compute_units)
+        .build();
+    let burn = Instruction::new_with_bytes(burner, &300_000u64.to_le_bytes(), vec![]);
+
+    let result = env.execute_as_transaction(&[burn.clone()], &[]);
+    assert_eq!(
+        result.error(),
+        Some(TransactionError::InstructionError(
+            0,
+            InstructionError::ComputationalBudgetExceeded
+        ))
+    );
+
+    env.set_compute_budget(ComputeBudget {
+        compute_unit_limit: 400_000,
+        ..ComputeBudget::default()
+    });
+    env.execute_as_transaction(&[burn], &[]).assert_success();
+}
+
+#[test]
+fn cpi_beyond_max_invoke_depth_fails() {
+    let wallet = random_keypair().pubkey();
+    let create_ata = |env: &LocalEnvironment| {
+        spl_associated_token_account::instruction::create_associated_token_account(
+            &env.payer().pubkey(),
+            &wallet,
+            &spl_token::native_mint::ID,
+            &spl_token::ID,
+        )
+    };
+
+    let mut env = LocalEnvironment::new();
+    env.execute_as_transaction(&[create_ata(&env)], &[])
+        .assert_success();
+
+    // the associated token program invokes the system and token programs
+    let mut env = LocalEnvironment::builder().set_max_invoke_depth(1).build();
+    let result = env.execute_as_transaction(&[create_ata(&env)], &[]);
+    assert_eq!(
+        result.error(),
+        Some(TransactionError::InstructionError(
+            0,
+            InstructionError::CallDepth
+        ))
+    );
+}
+
+/// Invokes itself as often as given in the first byte of the instruction data. Expects its own program id as the only account.
+fn invoke_recursively(invoke_context: &mut InvokeContext) -> Result<(), InstructionError> {
+    let transaction_context = &invoke_context.transaction_context;
+    let instruction_context = transaction_context.get_current_instruction_context()?;
+    let remaining = instruction_context.get_instruction_data()[0];
+    let program_id = *instruction_context.get_last_program_key(transaction_context)?;
+    if remaining == 0 {
+        return Ok(());
+    }
+    invoke_context.native_invoke(recursion(program_id, remaining - 1), &[])
+}
+
+fn recursion(program_id: Pubkey, depth: u8) -> Instruction {
+    Instruction::new_with_bytes(
+        program_id,
+        &[depth],
+        vec![AccountMeta::new_readonly(program_id, false)],
+    )
+}
+
+#[test]
+fn raised_max_invoke_depth_allows_deeper_cpi() {
+    let recursor = Pubkey::new_unique();
+    // five nested invocations reach a stack height of 6, one more than the default
+    let mut env = LocalEnvironment::builder()
+        .add_builtin("recursor", recursor, invoke_recursively)
+        .build();
+    let result = env.execute_as_transaction(&[recursion(recursor, 5)], &[]);
+    assert_eq!(
+        result.error(),
+        Some(TransactionError::InstructionError(
+            0,
+            InstructionError::CallDepth
+        ))
+    );
+
+    let mut env = LocalEnvironment::builder()
+        .add_builtin("recursor", recursor, invoke_recursively)
+        .set_max_invoke_depth(8)
+        .build();
+    env.execute_as_transaction(&[recursion(recursor, 5)], &[])
+        .assert_success();
+}
+
+#[test]
+fn warp_across_epoch_collects_rent() {
+    let paying = random_keypair().pubkey();
+    let exempt = random_keypair().pubkey();
+    let env = LocalEnvironment::builder()
+        .set_epoch_schedule(EpochSchedule::custom(432_000, 432_000, false))
+        .add_account_with_lamports(paying, system_program::ID, 100_000)
+        .add_account_with_lamports(exempt, system_program::ID, sol_to_lamports(1.0))
+        .build();
+
+    let env = env.warp_to_epoch(1);
+    assert!(env.get_account(paying).unwrap().lamports < 100_000);
+    assert_eq!(
+        env.get_account(exempt).unwrap().lamports,
+        sol_to_lamports(1.0)
+    );
+}
+
+#[test]
+fn custom_epoch_schedule_is_visible() {
+    let schedule = EpochSchedule::custom(1_000, 1_000, false);
+    let env = LocalEnvironment::builder()
+        .set_epoch_schedule(schedule)
+        .build();
+
+    let account = env.get_account(sysvar::epoch_schedule::ID).unwrap();
+    let on_chain: EpochSchedule = bincode::deserialize(&account.data).unwrap();
+    assert_eq!(on_chain, schedule);
+    assert_eq!(env.get_epoch_info().slots_in_epoch, 1_000);
+
+    let env = env.warp_to_epoch(2);
+    let epoch_info = env.get_epoch_info();
+    assert_eq!(epoch_info.epoch, 2);
+    assert_eq!(epoch_info.absolute_slot, 2_000);
+    assert_eq!(epoch_info.slots_in_epoch, 1_000);
+}
+
+/// Fails unless the instructions sysvar passed as first account holds as many instructions and the current index as given in the instruction data.
+fn check_instructions_sysvar(invoke_context: &mut InvokeContext) -> Result<(), InstructionError> {
+    let transaction_context = &invoke_context.transaction_context;
+    let instruction_context = transaction_context.get_current_instruction_context()?;
+    let expected = instruction_context.get_instruction_data();
+    let account = instruction_context.try_borrow_instruction_account(transaction_context, 0)?;
+    if *account.get_key() != sysvar::instructions::ID {
+        return Err(InstructionError::InvalidArgument);
+    }
+    let data = account.get_data();
+    let num_instructions = u16::from_le_bytes([data[0], data[1]]);
+    let current_index = u16::from_le_bytes([data[data.len() - 2], data[data.len() - 1]]);
+    if num_instructions != u16::from(expected[0]) || current_index != u16::from(expected[1]) {
+        return Err(InstructionError::InvalidAccountData);
+    }
+    Ok(())
+}
+
+#[test]
+fn instruction_introspection_sees_instructions_sysvar() {
+    let checker = Pubkey::new_unique();
+    let mut env = LocalEnvironment::builder()
+        .add_builtin("introspection_checker", checker, check_instructions_sysvar)
+        .build();
+
+    let signer = random_keypair();
+    let dalek_keypair = ed25519_dalek::Keypair::from_bytes(&signer.to_bytes()).unwrap();
+    let check = |current_index: u8| {
+        Instruction::new_with_bytes(
+            checker,
+            &[3, current_index],
+            vec![AccountMeta::new_readonly(sysvar::instructions::ID, false)],
+        )
+    };
+    env.execute_as_transaction(
+        &[
+            check(0),
+            new_ed25519_instruction(&dalek_keypair, b"poc-framework"),
+            check(2),
+        ],
+        &[],
+    )
+    .assert_success();
+}
+
+#[test]
+fn batch_with_different_fee_payers() {
+    let mut env = LocalEnvironment::new();
+    let faucet = env.payer().pubkey();
+    let faucet_balance = env.get_account(faucet).unwrap().lamports;
+    let alice = env.new_funded_account(sol_to_lamports(1.0));
+    let bob = env.new_funded_account(sol_to_lamports(1.0));
+    let recipient = random_keypair().pubkey();
+
+    let blockhash = env.get_latest_blockhash();
+    let txs = vec![
+        system_transaction::transfer(&alice, &recipient, 1_000_000, blockhash),
+        system_transaction::transfer(&bob, &recipient, 2_000_000, blockhash),
+    ];
+    let fee = env.estimate_fee(&txs[0].message);
+    for result in env.execute_transactions(txs) {
+        result.assert_success();
+    }
+
+    assert_eq!(
+        env.get_account(alice.pubkey()).unwrap().lamports,
+        sol_to_lamports(1.0) - 1_000_000 - fee
+    );
+    assert_eq!(
+        env.get_account(bob.pubkey()).unwrap().lamports,
+        sol_to_lamports(1.0) - 2_000_000 - fee
+    );
+    assert_eq!(env.get_account(recipient).unwrap().lamports, 3_000_000);
+    assert_eq!(env.get_account(faucet).unwrap().lamports, faucet_balance);
+}
+
+#[test]
+#[should_panic(expected = "lamports left in the batch")]
+fn batch_with_unfunded_fee_payer_panics() {
+    let mut env = LocalEnvironment::new();
+    let alice = env.new_funded_account(sol_to_lamports(1.0));
+    let bob = random_keypair();
+    let recipient = random_keypair().pubkey();
+
+    let blockhash = env.get_latest_blockhash();
+    env.execute_transactions(vec![
+        system_transaction::transfer(&alice, &recipient, 1_000_000, blockhash),
+        system_transaction::transfer(&bob, &recipient, 1_000_000, blockhash),
+    ]);
+}
+
+#[test]
+fn advance_slots_moves_one_blockhash_per_slot() {
+    let env = LocalEnvironment::new();
+    let slot = env.get_epoch_info().absolute_slot;
+    let blockhash = env.get_latest_blockhash();
+
+    let env = env.advance_slots(0);
+    assert_eq!(env.get_epoch_info().absolute_slot, slot);
+    assert_eq!(env.get_latest_blockhash(), blockhash);
+
+    let env = env.advance_slots(1);
+    assert_eq!(env.get_epoch_info().absolute_slot, slot + 1);
+    assert_ne!(env.get_latest_blockhash(), blockhash);
+
+    let env = env.advance_slots(50);
+    assert_eq!(env.get_epoch_info().absolute_slot, slot + 51);
+}
+
+#[test]
+fn fork_starts_with_fresh_sysvars() {
+    let mut env = LocalEnvironment::new().advance_slots(10);
+    let account = env.new_funded_account(1_000_000);
+
+    let fork = env.fork();
+    assert_eq!(
+        fork.get_account(account.pubkey()).unwrap().lamports,
+        1_000_000
+    );
+    let slot_hashes = fork
+        .get_account(sysvar::slot_hashes::ID)
+        .map(|account| bincode::deserialize::<SlotHashes>(&account.data).unwrap());
+    assert!(slot_hashes.map_or(true, |slot_hashes| slot_hashes.is_empty()));
+}
+
+#[test]
+fn load_state_restores_blockhash_and_settings() {
+    let env = LocalEnvironment::builder()
+        .enforce_blockhash_expiry(true)
+        .set_verify_signatures(false)
+        .build()
+        .advance_slots(3);
+    let recipient = random_keypair().pubkey();
+    let path = std::env::temp_dir().join(format!("poc-framework-{}.state", recipient));
+    env.save_state(&path).unwrap();
+
+    let mut loaded = LocalEnvironmentBuilder::load_state(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        loaded.get_epoch_info().absolute_slot,
+        env.get_epoch_info().absolute_slot
+    );
+    assert_eq!(loaded.get_latest_blockhash(), env.get_latest_blockhash());
+
+    // only succeeds if the saved blockhash is known and signatures are still not verified
+    let mut tx = system_transaction::transfer(
+        &loaded.payer(),
+        &recipient,
+        1_000_000,
+        env.get_latest_blockhash(),
+    );
+    tx.signatures[0] = Signature::default();
+    loaded.execute_transaction(tx).assert_success();
+    assert_eq!(loaded.get_account(recipient).unwrap().lamports, 1_000_000);
+}
+
+#[test]
+fn unknown_blockhash_fails_transaction() {
+    let mut env = LocalEnvironment::new();
+    let tx = system_transaction::transfer(
+        &env.payer(),
+        &random_keypair().pubkey(),
+        1_000_000,
+        Hash::new_unique(),
+    );
+    let result = env.execute_transaction(tx);
+    assert_eq!(result.error(), Some(TransactionError::BlockhashNotFound));
+}